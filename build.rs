@@ -3,6 +3,18 @@ fn main() {
     config.btree_map(&["."]);
     tonic_build::configure()
         .type_attribute("runison.Point", "#[derive(Hash)]")
+        .type_attribute(
+            "runison.Node",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
+        .type_attribute(
+            "runison.Owner",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
+        .type_attribute(
+            "runison.Entries",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
         .compile(&["proto/runison/runison.proto"], &["proto"])
         .unwrap()
 }
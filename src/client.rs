@@ -1,18 +1,36 @@
 mod config;
+mod delta;
+mod filter;
 mod node;
+mod reconcile;
+mod report;
 mod synchronizer;
+mod tls;
+mod transfer;
+mod ui;
 
+use config::ConflictPolicy;
 use synchronizer::Synchronizer;
-use tonic::Request;
+use tonic::{
+    metadata::{AsciiMetadataValue, MetadataValue},
+    transport::Channel,
+    Request,
+};
 
 use runison::synchronizer_client::SynchronizerClient;
-use runison::{ChangeSetResponse, Entries, Feature, Point, Rectangle, RouteNote, RouteSummary};
+use runison::{
+    Action, Change, ChangeSetRequest, ChangeSetResponse, ChangeType, CommitRequest, Feature, Node,
+    Plan, PlanItem, Point, Rectangle, RouteNote, RouteSummary,
+};
 
 pub mod runison {
     tonic::include_proto!("runison");
 }
 
+use std::collections::BTreeSet;
+use std::fs;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -23,38 +41,207 @@ struct Opt {
     #[structopt(short, long)]
     debug: bool,
 
-    /// Synchronization server
-    // we don't want to name it "speed", need to look smart
+    /// Synchronization server, overriding [remote] address
     #[structopt(short = "s", long = "server")]
-    server: String,
+    server: Option<String>,
+
+    /// Named root on the server, overriding [remote] root
+    #[structopt(short = "r", long = "root")]
+    root: Option<String>,
+
+    /// Only synchronize this subtree of the root, which may be given
+    /// more than once
+    #[structopt(short = "p", long = "path")]
+    paths: Vec<String>,
 
     /// Configuration file
     #[structopt(short = "c", long = "config", parse(from_os_str))]
     config: PathBuf,
+
+    /// Apply all non-conflicting changes without prompting
+    #[structopt(short = "b", long = "batch")]
+    batch: bool,
+
+    /// How a batch run resolves conflicts: skip, prefer-client,
+    /// prefer-server, prefer-newer or keep-both
+    #[structopt(long = "conflict")]
+    conflict: Option<ConflictPolicy>,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Show what a synchronization would do, without changing anything
+    Plan {
+        /// Print the plan as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Explain whether a path is ignored, and which rule decides it
+    CheckIgnore {
+        /// Path relative to the root
+        path: String,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opt = Opt::from_args();
-    println!("{:?}", opt);
+    eprintln!("{:?}", opt);
     let result = config::get_config(opt.config);
     match result {
         Ok(config) => {
+            let policy = opt.conflict.unwrap_or(config.conflict.policy);
+            let tls = tls::client_config(&config.tls)?;
+            let scheme = if tls.is_some() { "https" } else { "http" };
+            let address = opt.server.clone().or_else(|| config.remote.address.clone());
+            let token = config.remote.token.clone();
+            let root = opt.root.clone().or_else(|| config.remote.root.clone());
+            if config.root.path.is_empty() {
+                return Err("no [root] path to synchronize".into());
+            }
             // create a synchronizer
-            let mut synchronizer = Synchronizer::new(config).unwrap();
+            let mut synchronizer = Synchronizer::new(config)?;
+            if let Some(Command::CheckIgnore { path }) = &opt.cmd {
+                match synchronizer.local_path(path) {
+                    Some(path) => println!("{}", synchronizer.filter.explain(&path)),
+                    None => return Err(format!("invalid path {}", path).into()),
+                }
+                return Ok(());
+            }
+            let address = match address {
+                Some(address) if address.contains("://") => address,
+                Some(address) => format!("{}://{}", scheme, address),
+                None => return Err("no server given, use --server or [remote] address".into()),
+            };
+            synchronizer.dry_run = opt.cmd.is_some();
+            if !opt.paths.is_empty() {
+                synchronizer.select(&opt.paths);
+                if synchronizer.paths.is_empty() {
+                    return Err(
+                        "--path selects nothing inside the root and its [path] directories".into(),
+                    );
+                }
+            }
             // index local files
             synchronizer.index();
 
             // create a client
-            let mut client = SynchronizerClient::connect("http://[::1]:10000").await?;
+            let mut endpoint = Channel::from_shared(address)?;
+            if let Some(tls) = tls {
+                endpoint = endpoint.tls_config(tls)?;
+            }
+            let channel = endpoint.connect().await?;
+            // every request carries the pre-shared token and the root it is for
+            let authorization = token
+                .map(|token| MetadataValue::from_str(&format!("Bearer {}", token)))
+                .transpose()?;
+            let root = root
+                .map(|root| MetadataValue::from_str(&root))
+                .transpose()?;
+            // and, once the server planned it, the synchronization it belongs to
+            let session: Arc<Mutex<Option<AsciiMetadataValue>>> = Arc::default();
+            let current = Arc::clone(&session);
+            let mut client =
+                SynchronizerClient::with_interceptor(channel, move |mut request: Request<()>| {
+                    if let Some(authorization) = &authorization {
+                        request
+                            .metadata_mut()
+                            .insert("authorization", authorization.clone());
+                    }
+                    if let Some(root) = &root {
+                        request.metadata_mut().insert("runison-root", root.clone());
+                    }
+                    let current = current.lock().unwrap_or_else(|e| e.into_inner());
+                    if let Some(session) = current.as_ref() {
+                        request
+                            .metadata_mut()
+                            .insert("runison-session", session.clone());
+                    }
+                    Ok(request)
+                });
 
-            println!("*** Get ChangeSet ***");
+            eprintln!("*** Get ChangeSet ***");
             let response = client
-                .get_change_set(Request::new(Entries {
-                    nodes: synchronizer.entries.nodes,
+                .get_change_set(Request::new(ChangeSetRequest {
+                    current: Some(synchronizer.entries.clone()),
+                    archive: synchronizer.archive.clone(),
+                    dry_run: synchronizer.dry_run,
+                    paths: synchronizer.paths.clone(),
+                    names: Some(synchronizer.names()),
+                    replica: replica(&synchronizer.config.root.path),
                 }))
-                .await?;
-            println!("RESPONSE = {:?}", response);
+                .await?
+                .into_inner();
+            if !response.session.is_empty() {
+                *session.lock().unwrap_or_else(|e| e.into_inner()) =
+                    Some(MetadataValue::from_str(&response.session)?);
+            }
+            // the server may synchronize fewer subtrees than were asked for
+            synchronizer.select(&response.paths);
+            if let (Some(plan), Some(Command::Plan { json })) = (&response.plan, &opt.cmd) {
+                if *json {
+                    report::print_json(plan)?;
+                } else {
+                    report::print_table(plan);
+                }
+                return Ok(());
+            }
+            if let Some(plan) = response.plan {
+                let choices = if opt.batch {
+                    let choices = batch_choices(&plan, policy);
+                    ui::print_choices(&plan, &choices);
+                    choices
+                } else {
+                    ui::review(&plan, &mut client, &synchronizer).await?
+                };
+                // paths the server left out of the plan, because this
+                // client may not read them, stay as they were archived
+                let planned: BTreeSet<&String> =
+                    plan.items.iter().map(|item| &item.relative_path).collect();
+                let mut unsynchronized: Vec<String> = synchronizer
+                    .entries
+                    .nodes
+                    .keys()
+                    .chain(synchronizer.archive.iter().flat_map(|a| a.nodes.keys()))
+                    .filter(|path| !planned.contains(path))
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .cloned()
+                    .collect();
+                for (item, choice) in plan.items.iter().zip(choices) {
+                    let result = match choice.and_then(|action| item.change(action)) {
+                        Some(change) => apply(&mut client, &mut synchronizer, &change).await,
+                        None if opt.batch && item.keeps_both(policy) => {
+                            keep_both(&mut client, &mut synchronizer, &plan, item).await
+                        }
+                        // skipped or conflicting, try again next time
+                        None if !item.in_sync() => {
+                            unsynchronized.push(item.relative_path.clone());
+                            continue;
+                        }
+                        None => continue,
+                    };
+                    if let Err(e) = result {
+                        println!("Error: {:?}", e);
+                        unsynchronized.push(item.relative_path.clone());
+                    }
+                }
+                // both replicas record the synchronized state in their archives
+                client
+                    .commit(Request::new(CommitRequest {
+                        unsynchronized: unsynchronized.clone(),
+                    }))
+                    .await?;
+                for path in &unsynchronized {
+                    synchronizer.keep_archived(path);
+                }
+                if let Err(e) = synchronizer.save_archive() {
+                    println!("Error: {:?}", e);
+                }
+            }
         }
         Err(error) => {
             println!("Error: {:?}", error);
@@ -62,3 +249,206 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
     Ok(())
 }
+
+// identifies this replica to the server, which keeps an archive for
+// every client replica: the host name and the path of the root
+fn replica(root: &str) -> String {
+    let mut host = [0u8; 256];
+    let len = match unsafe { libc::gethostname(host.as_mut_ptr() as *mut libc::c_char, host.len()) }
+    {
+        0 => host.iter().position(|&b| b == 0).unwrap_or(host.len()),
+        _ => 0,
+    };
+    let root = fs::canonicalize(root).unwrap_or_else(|_| PathBuf::from(root));
+    format!(
+        "{}:{}",
+        String::from_utf8_lossy(&host[..len]),
+        root.display()
+    )
+}
+
+// the actions a batch run takes: every suggested action, plus
+// whatever the conflict policy picks for conflicting paths
+fn batch_choices(plan: &Plan, policy: ConflictPolicy) -> Vec<Option<Action>> {
+    plan.items
+        .iter()
+        .map(|item| match item.action() {
+            Action::Conflict => item.resolve(policy),
+            _ => item.default_action(),
+        })
+        .collect()
+}
+
+// resolve a conflict by propagating the client copy, and keeping the
+// server copy next to it under a new name on both replicas. the server
+// copies its own file, which the client then receives like any other
+async fn keep_both(
+    client: &mut SynchronizerClient<Channel>,
+    synchronizer: &mut Synchronizer,
+    plan: &Plan,
+    item: &PlanItem,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (change, server) = match (item.change(Action::LeftToRight), &item.server) {
+        (Some(change), Some(server)) => (change, server),
+        _ => return Err(format!("can't keep both copies of {}", item.relative_path).into()),
+    };
+    let renamed = conflict_name(synchronizer, plan, &item.relative_path);
+    client
+        .apply_change(Request::new(Change {
+            change_type: ChangeType::Servercopy as i32,
+            node: Some(server.clone()),
+            target: renamed.clone(),
+        }))
+        .await?;
+    // a copy of its own, whatever the server file was linked to
+    let copy = Node {
+        relative_path: renamed.clone(),
+        hard_link: String::new(),
+        ..server.clone()
+    };
+    let received = Change {
+        change_type: ChangeType::Serveradd as i32,
+        node: Some(copy),
+        target: String::new(),
+    };
+    apply(client, synchronizer, &received).await?;
+    apply(client, synchronizer, &change).await?;
+    println!("Kept server copy of {} as {}", item.relative_path, renamed);
+    Ok(())
+}
+
+// a name for the server copy of a conflicting file that is free on
+// both replicas: no path of the plan, nor one that exists locally
+fn conflict_name(synchronizer: &Synchronizer, plan: &Plan, relative_path: &str) -> String {
+    let planned: BTreeSet<&String> = plan.items.iter().map(|item| &item.relative_path).collect();
+    (1..)
+        .map(|n| match n {
+            1 => format!("{}.server-conflict", relative_path),
+            n => format!("{}.server-conflict-{}", relative_path, n),
+        })
+        .find(|name| {
+            !planned.contains(name)
+                && !synchronizer.entries.nodes.contains_key(name)
+                && synchronizer
+                    .local_path(name)
+                    .map_or(true, |path| fs::symlink_metadata(path).is_err())
+        })
+        .unwrap()
+}
+
+// carry out a single change on whichever replica receives it
+async fn apply(
+    client: &mut SynchronizerClient<Channel>,
+    synchronizer: &mut Synchronizer,
+    change: &Change,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let node = match &change.node {
+        Some(node) => node,
+        None => return Ok(()),
+    };
+    match change.change_type() {
+        // a hard link is made on the server from its leader, and only
+        // sent in full if that fails
+        ChangeType::Clientadd | ChangeType::Clientmodify
+            if node.file
+                && !node.hard_link.is_empty()
+                && client
+                    .apply_change(Request::new(change.clone()))
+                    .await
+                    .is_ok() =>
+        {
+            println!(
+                "Linked {} to {} on server",
+                node.relative_path, node.hard_link
+            );
+        }
+        ChangeType::Clientadd | ChangeType::Clientmodify if node.file => {
+            let path = match synchronizer.source_path(&node.relative_path) {
+                Some(path) => path,
+                None => return Err(format!("invalid path {}", node.relative_path).into()),
+            };
+            let transfer = synchronizer.config.transfer.clone();
+            // the holes of sparse files are cheaper to skip than to diff
+            let len = if change.change_type() == ChangeType::Clientmodify
+                && node.len >= delta::DELTA_MIN_SIZE
+                && !node.sparse
+            {
+                match delta::push_delta(client, path.clone(), node.clone()).await {
+                    Ok(len) => len,
+                    Err(e) => {
+                        println!("Delta failed ({}), sending whole file", e);
+                        transfer::push(client, path, node.clone(), transfer).await?
+                    }
+                }
+            } else {
+                transfer::push(client, path, node.clone(), transfer).await?
+            };
+            println!("Sent {} ({} bytes)", node.relative_path, len);
+        }
+        ChangeType::Clientadd | ChangeType::Clientmodify if node.dir || node.symlink => {
+            client.apply_change(Request::new(change.clone())).await?;
+        }
+        ChangeType::Clientmetadata => {
+            client.apply_change(Request::new(change.clone())).await?;
+            println!("Updated attributes of {} on server", node.relative_path);
+        }
+        ChangeType::Clientdelete => {
+            client.apply_change(Request::new(change.clone())).await?;
+            println!("Deleted {} on server", node.relative_path);
+        }
+        ChangeType::Serveradd | ChangeType::Servermodify
+            if node.file && synchronizer.hard_link(node).is_ok() =>
+        {
+            synchronizer.apply_attributes(node)?;
+            synchronizer.record(&node.relative_path);
+            println!("Linked {} to {}", node.relative_path, node.hard_link);
+        }
+        ChangeType::Serveradd | ChangeType::Servermodify if node.file => {
+            let basis = synchronizer.source_path(&node.relative_path);
+            let staged = synchronizer.stage(&node.relative_path)?;
+            let len = match basis {
+                Some(basis)
+                    if change.change_type() == ChangeType::Servermodify
+                        && node.len >= delta::DELTA_MIN_SIZE
+                        && !node.sparse =>
+                {
+                    match delta::pull_delta(client, &basis, staged, node).await {
+                        Ok(len) => len,
+                        Err(e) => {
+                            println!("Delta failed ({}), receiving whole file", e);
+                            let staged = synchronizer.stage(&node.relative_path)?;
+                            transfer::pull(client, staged, node).await?
+                        }
+                    }
+                }
+                _ => transfer::pull(client, staged, node).await?,
+            };
+            synchronizer.apply_attributes(node)?;
+            synchronizer.record(&node.relative_path);
+            println!("Received {} ({} bytes)", node.relative_path, len);
+        }
+        ChangeType::Serveradd | ChangeType::Servermodify if node.dir => {
+            synchronizer.make_dir(&node.relative_path)?;
+            synchronizer.apply_attributes(node)?;
+            synchronizer.record(&node.relative_path);
+        }
+        ChangeType::Serveradd | ChangeType::Servermodify if node.symlink => {
+            synchronizer.make_link(node)?;
+            synchronizer.record(&node.relative_path);
+        }
+        ChangeType::Servermetadata => {
+            synchronizer.apply_attributes(node)?;
+            synchronizer.record(&node.relative_path);
+            println!("Updated attributes of {}", node.relative_path);
+        }
+        ChangeType::Serverdelete => {
+            synchronizer.remove(node)?;
+            synchronizer.record(&node.relative_path);
+            println!("Deleted {}", node.relative_path);
+        }
+        change_type => {
+            println!("Skipping {:?} of {}", change_type, node.relative_path);
+        }
+    }
+    Ok(())
+}
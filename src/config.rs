@@ -3,29 +3,364 @@ use figment::{
     Error, Figment,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 #[derive(Clone, PartialEq, Deserialize)]
 pub struct Config {
+    // a server serving only named roots has no [root] of its own
+    #[serde(default)]
     pub root: Root,
+    #[serde(default)]
     pub path: Path,
+    #[serde(default)]
     pub ignore: Ignore,
+    // further replicas a server serves, by name
+    #[serde(default)]
+    pub roots: BTreeMap<String, NamedRoot>,
+    #[serde(default)]
+    pub conflict: Conflict,
+    #[serde(default)]
+    pub server: Server,
+    #[serde(default)]
+    pub remote: Remote,
+    #[serde(default)]
+    pub tls: Tls,
+    #[serde(default)]
+    pub attributes: Attributes,
+    #[serde(default)]
+    pub links: Links,
+    #[serde(default)]
+    pub transfer: Transfer,
+    #[serde(default)]
+    pub names: Names,
+    // clients allowed to use this server, by name. without any,
+    // the server accepts every client that can reach it
+    #[serde(default)]
+    pub clients: BTreeMap<String, Client>,
 }
 
-#[derive(Clone, PartialEq, Deserialize)]
+#[derive(Clone, PartialEq, Default, Deserialize)]
 pub struct Root {
     pub path: String,
 }
-#[derive(Clone, PartialEq, Deserialize)]
+#[derive(Clone, PartialEq, Default, Deserialize)]
 
 pub struct Path {
     pub directories: Vec<String>,
 }
 
 #[derive(Clone, PartialEq, Deserialize)]
+pub struct NamedRoot {
+    pub path: String,
+    // default to the top-level [path] and [ignore] sections
+    pub directories: Option<Vec<String>>,
+    pub ignore: Option<Ignore>,
+}
+
+// gitignore patterns relative to the root. patterns without a slash
+// match names at any depth, a trailing slash only matches directories
+// and a leading ! includes a path again
+#[derive(Clone, PartialEq, Default, Deserialize)]
 pub struct Ignore {
+    #[serde(default)]
     pub name: Vec<String>,
+    #[serde(default)]
     pub path: Vec<String>,
+    // regular expressions matching whole paths relative to the root
+    #[serde(default)]
+    pub regex: Vec<String>,
+    // exceptions, paths these match are never ignored
+    #[serde(default)]
+    pub not: Vec<String>,
+    #[serde(default)]
+    pub not_regex: Vec<String>,
+    // honor .gitignore files next to .runisonignore files
+    #[serde(default)]
+    pub gitignore: bool,
+}
+
+// file attributes recorded and applied besides the contents
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct Attributes {
+    // permission bits, including the executable bits
+    #[serde(default = "enabled")]
+    pub permissions: bool,
+    // owner and group, only applied when running as root
+    #[serde(default)]
+    pub owner: bool,
+    // modification time of files
+    #[serde(default = "enabled")]
+    pub mtime: bool,
+    // access time of files
+    #[serde(default)]
+    pub atime: bool,
+    // prefixes of the names of extended attributes to synchronize,
+    // e.g. "user." or "system.posix_acl_" for ACLs
+    #[serde(default)]
+    pub xattrs: Vec<String>,
+}
+
+impl Default for Attributes {
+    fn default() -> Self {
+        Attributes {
+            permissions: true,
+            owner: false,
+            mtime: true,
+            atime: false,
+            xattrs: Vec::new(),
+        }
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Clone, PartialEq, Default, Deserialize)]
+pub struct Links {
+    // synchronize what symlinks point to instead of the links themselves.
+    // dangling links are still synchronized as links
+    #[serde(default)]
+    pub follow: bool,
+    // neither send nor create links pointing outside of the root
+    #[serde(default)]
+    pub reject_escaping: bool,
+    // transfer the contents of hard linked files once, and link
+    // them together again on the other replica
+    #[serde(default)]
+    pub hard: bool,
+}
+
+// how the file system of the root treats names. set these when it is
+// mounted from one that ignores case or normalizes Unicode names, like
+// many SMB shares, so names the other replica keeps apart aren't merged
+#[derive(Clone, PartialEq, Default, Deserialize)]
+pub struct Names {
+    // names that only differ in case are the same file
+    #[serde(default)]
+    pub case_insensitive: bool,
+    // names that only differ in their Unicode normalization are the same file
+    #[serde(default)]
+    pub normalization: bool,
+}
+
+// how file contents are sent between the replicas
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct Transfer {
+    // send only the data of sparse files, the receiver recreates the holes
+    #[serde(default = "enabled")]
+    pub sparse: bool,
+    // files of at least this many bytes are streamed in small chunks,
+    // with a single chunk read ahead of what has been sent
+    #[serde(default = "default_stream_size")]
+    pub stream_size: u64,
+}
+
+impl Default for Transfer {
+    fn default() -> Self {
+        Transfer {
+            sparse: true,
+            stream_size: default_stream_size(),
+        }
+    }
+}
+
+fn default_stream_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct Server {
+    // address runison-server listens on
+    #[serde(default = "default_listen")]
+    pub listen: String,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server {
+            listen: default_listen(),
+        }
+    }
+}
+
+fn default_listen() -> String {
+    String::from("[::1]:10000")
+}
+
+#[derive(Clone, PartialEq, Default, Deserialize)]
+pub struct Remote {
+    // server the client synchronizes with, e.g. "http://host:10000"
+    pub address: Option<String>,
+    // token identifying this client to the server
+    pub token: Option<String>,
+    // named root to synchronize with, the server's [root] if unset
+    pub root: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct Client {
+    // pre-shared token the client authenticates with
+    pub token: String,
+    // access to the server's [root]
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub write: Vec<String>,
+    // access to named roots, by name
+    #[serde(default)]
+    pub roots: BTreeMap<String, Acl>,
+}
+
+#[derive(Clone, PartialEq, Default, Deserialize)]
+pub struct Acl {
+    // paths relative to the root the client may read, "." for all of it
+    #[serde(default)]
+    pub read: Vec<String>,
+    // paths the client may write, which it may read as well
+    #[serde(default)]
+    pub write: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+impl Client {
+    // the access a client has to the [root] replica for None, or to a
+    // named root. None if it may not use that root at all
+    pub fn acl(&self, root: Option<&str>) -> Option<Acl> {
+        match root {
+            None => Some(Acl {
+                read: self.read.clone(),
+                write: self.write.clone(),
+            }),
+            Some(name) => self.roots.get(name).cloned(),
+        }
+    }
+}
+
+impl Acl {
+    // returns true if the client may access a path in the given way
+    pub fn allows(&self, relative_path: &str, access: Access) -> bool {
+        let within = |prefixes: &[String]| {
+            prefixes
+                .iter()
+                .any(|prefix| path_within(relative_path, prefix))
+        };
+        match access {
+            Access::Read => within(&self.read) || within(&self.write),
+            Access::Write => within(&self.write),
+        }
+    }
+}
+
+// the subtrees two selections of subtrees have in common
+pub fn intersect(left: &[String], right: &[String]) -> Vec<String> {
+    let mut common = Vec::new();
+    for l in left {
+        for r in right {
+            let narrower = if path_within(l, r) {
+                l
+            } else if path_within(r, l) {
+                r
+            } else {
+                continue;
+            };
+            if !common.contains(narrower) {
+                common.push(narrower.clone());
+            }
+        }
+    }
+    common
+}
+
+// returns true if a relative path is a prefix path or lies below it
+pub fn path_within(relative_path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() || prefix == "." {
+        return true;
+    }
+    match relative_path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+#[derive(Clone, PartialEq, Default, Deserialize)]
+pub struct Tls {
+    // PEM certificate and private key this side presents
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    // PEM certificate of the CA the other side must be signed by
+    pub ca: Option<PathBuf>,
+    // name the client expects on the server certificate,
+    // defaults to the host of the server address
+    pub domain: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Default, Deserialize)]
+pub struct Conflict {
+    // how batch runs resolve conflicting paths
+    #[serde(default)]
+    pub policy: ConflictPolicy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    // leave the conflict for an interactive run
+    Skip,
+    // propagate the client copy
+    PreferClient,
+    // propagate the server copy
+    PreferServer,
+    // propagate the most recently modified copy
+    PreferNewer,
+    // propagate the client copy and keep the server copy under a new name
+    KeepBoth,
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> Self {
+        ConflictPolicy::Skip
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(ConflictPolicy::Skip),
+            "prefer-client" => Ok(ConflictPolicy::PreferClient),
+            "prefer-server" => Ok(ConflictPolicy::PreferServer),
+            "prefer-newer" => Ok(ConflictPolicy::PreferNewer),
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            _ => Err(format!("unknown conflict policy {}", s)),
+        }
+    }
+}
+
+impl Config {
+    // the configuration of a named root, which is the configuration
+    // of a replica of its own
+    pub fn root_config(&self, name: &str) -> Option<Config> {
+        let root = self.roots.get(name)?;
+        let mut config = self.clone();
+        config.root.path = root.path.clone();
+        if let Some(directories) = &root.directories {
+            config.path.directories = directories.clone();
+        }
+        if let Some(ignore) = &root.ignore {
+            config.ignore = ignore.clone();
+        }
+        config.roots.clear();
+        Some(config)
+    }
 }
 
 pub fn get_config(path: PathBuf) -> Result<Config, figment::Error> {
@@ -0,0 +1,497 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Channel, Request};
+
+use crate::runison::delta::Op;
+use crate::runison::synchronizer_client::SynchronizerClient;
+use crate::runison::*;
+use crate::transfer::{Staged, CHUNK_SIZE};
+
+// files smaller than this are cheaper to send whole
+pub const DELTA_MIN_SIZE: u64 = 1024 * 1024;
+
+// block size for a file of len bytes: roughly its square root,
+// like rsync, within sensible bounds
+pub fn block_size(len: u64) -> u32 {
+    ((len as f64).sqrt() as u32).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+const MIN_BLOCK_SIZE: u32 = 700;
+const MAX_BLOCK_SIZE: u32 = 128 * 1024;
+
+// a block size from the other end decides how much is buffered, and
+// one of 0 would never advance
+pub fn check_block_size(block_size: u32) -> io::Result<()> {
+    if (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid block size {}", block_size),
+        ))
+    }
+}
+
+// rsync's rolling checksum over a window of bytes
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Rolling {
+        let mut rolling = Rolling {
+            a: 0,
+            b: 0,
+            len: window.len() as u32,
+        };
+        for (i, &x) in window.iter().enumerate() {
+            let weight = (window.len() - i) as u32;
+            rolling.a = rolling.a.wrapping_add(x as u32);
+            rolling.b = rolling.b.wrapping_add(weight.wrapping_mul(x as u32));
+        }
+        rolling
+    }
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+    // slide the window by one byte
+    fn roll(&mut self, old: u8, new: u8) {
+        self.a = self.a.wrapping_sub(old as u32).wrapping_add(new as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(old as u32))
+            .wrapping_add(self.a);
+    }
+}
+
+fn strong(block: &[u8]) -> Vec<u8> {
+    blake3::hash(block).as_bytes()[..16].to_vec()
+}
+
+// read until buf is full or the reader is exhausted
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// compute the block signature of a file
+pub fn signature(path: &Path, relative_path: &str) -> io::Result<Signature> {
+    let mut file = File::open(path)?;
+    let block_size = block_size(file.metadata()?.len());
+    let mut blocks = Vec::new();
+    let mut buf = vec![0; block_size as usize];
+    loop {
+        let n = read_full(&mut file, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        blocks.push(BlockSignature {
+            weak: Rolling::new(&buf[..n]).digest(),
+            strong: strong(&buf[..n]),
+        });
+        if n < buf.len() {
+            break;
+        }
+    }
+    Ok(Signature {
+        relative_path: relative_path.to_string(),
+        block_size,
+        blocks,
+    })
+}
+
+// compare a file against the signature of the other replica's copy and
+// hand send the operations that rebuild the file from that copy. the
+// file is read in chunks, so memory use doesn't grow with its size.
+// send returns false once the other end has gone away
+pub fn diff<F>(path: &Path, signature: &Signature, mut send: F) -> io::Result<()>
+where
+    F: FnMut(Op) -> bool,
+{
+    check_block_size(signature.block_size)?;
+    let block_size = signature.block_size as usize;
+    let mut blocks: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, block) in signature.blocks.iter().enumerate() {
+        blocks.entry(block.weak).or_default().push(i);
+    }
+
+    let mut file = File::open(path)?;
+    let mut buf = Vec::new();
+    let mut eof = false;
+    // start of the window being matched, and of the literal data
+    // that hasn't been sent yet
+    let mut start = 0;
+    let mut literal = 0;
+    let mut rolling: Option<Rolling> = None;
+    loop {
+        if buf.len() - start < block_size {
+            if eof {
+                break;
+            }
+            // drop what has been sent and read some more
+            buf.drain(..literal);
+            start -= literal;
+            literal = 0;
+            let mut more = vec![0; CHUNK_SIZE.max(block_size)];
+            let n = read_full(&mut file, &mut more)?;
+            eof = n < more.len();
+            buf.extend_from_slice(&more[..n]);
+            continue;
+        }
+        let window = &buf[start..start + block_size];
+        let weak = match &rolling {
+            Some(rolling) => rolling.digest(),
+            None => {
+                let fresh = Rolling::new(window);
+                let digest = fresh.digest();
+                rolling = Some(fresh);
+                digest
+            }
+        };
+        let matched = blocks.get(&weak).and_then(|candidates| {
+            let hash = strong(window);
+            candidates
+                .iter()
+                .find(|&&i| signature.blocks[i].strong == hash)
+                .copied()
+        });
+        match matched {
+            Some(block) => {
+                if literal < start && !send(Op::Data(buf[literal..start].to_vec())) {
+                    return Ok(());
+                }
+                if !send(Op::Copy(block as u64)) {
+                    return Ok(());
+                }
+                start += block_size;
+                literal = start;
+                rolling = None;
+            }
+            None => {
+                // the next byte may not be buffered yet, in which case
+                // the checksum is computed afresh after reading more
+                match (&mut rolling, buf.get(start + block_size)) {
+                    (Some(r), Some(&next)) => r.roll(buf[start], next),
+                    _ => rolling = None,
+                }
+                start += 1;
+                if start - literal >= CHUNK_SIZE {
+                    if !send(Op::Data(buf[literal..start].to_vec())) {
+                        return Ok(());
+                    }
+                    literal = start;
+                }
+            }
+        }
+    }
+    // the tail is shorter than a block and can't match
+    if literal < buf.len() {
+        send(Op::Data(buf[literal..].to_vec()));
+    }
+    Ok(())
+}
+
+// rebuilds a file from the replica's existing copy and a delta stream
+pub struct Patcher {
+    basis: File,
+    basis_len: u64,
+    block_size: u64,
+    staged: Staged,
+    offset: u64,
+}
+
+impl Patcher {
+    // block_size is the one the deltas were built against, which is
+    // the one a signature of the basis gets unless the basis changed
+    pub fn new(basis: &Path, staged: Staged, block_size: u32) -> io::Result<Patcher> {
+        check_block_size(block_size)?;
+        let basis = File::open(basis)?;
+        let basis_len = basis.metadata()?.len();
+        if self::block_size(basis_len) != block_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the file changed since its signature was taken",
+            ));
+        }
+        Ok(Patcher {
+            basis,
+            basis_len,
+            block_size: block_size as u64,
+            staged,
+            offset: 0,
+        })
+    }
+    pub fn apply(&mut self, op: &Op) -> io::Result<()> {
+        let len = match op {
+            Op::Copy(block) => {
+                if block.saturating_mul(self.block_size) >= self.basis_len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("block {} is past the end of the file", block),
+                    ));
+                }
+                let mut buf = vec![0; self.block_size as usize];
+                self.basis.seek(SeekFrom::Start(block * self.block_size))?;
+                let n = read_full(&mut self.basis, &mut buf)?;
+                self.staged.write_at(self.offset, &buf[..n])?;
+                n
+            }
+            Op::Data(data) => {
+                self.staged.write_at(self.offset, data)?;
+                data.len()
+            }
+        };
+        self.offset += len as u64;
+        Ok(())
+    }
+    pub fn commit(self, node: &Node) -> io::Result<u64> {
+        self.staged.commit(node)
+    }
+}
+
+// send a modified file to the server as a delta against the server's
+// copy, returning the number of bytes the server wrote
+pub async fn push_delta(
+    client: &mut SynchronizerClient<Channel>,
+    path: PathBuf,
+    node: Node,
+) -> Result<u64, Box<dyn Error>> {
+    let signature = client
+        .get_signature(Request::new(FileRequest {
+            relative_path: node.relative_path.clone(),
+        }))
+        .await?
+        .into_inner();
+    let (tx, rx) = mpsc::channel(4);
+    let differ = tokio::task::spawn_blocking(move || {
+        let relative_path = node.relative_path.clone();
+        // the first delta carries the node and the block size
+        let mut first = Some((node, signature.block_size));
+        diff(&path, &signature, |op| {
+            let (node, block_size) = match first.take() {
+                Some((node, block_size)) => (Some(node), block_size),
+                None => (None, 0),
+            };
+            tx.blocking_send(Delta {
+                relative_path: relative_path.clone(),
+                node,
+                block_size,
+                op: Some(op),
+            })
+            .is_ok()
+        })
+    });
+    let response = client
+        .push_delta(Request::new(ReceiverStream::new(rx)))
+        .await?;
+    differ.await??;
+    Ok(response.into_inner().len)
+}
+
+// rebuild a modified server file from the local copy at basis and the
+// deltas the server sends against it. returns the number of bytes written
+pub async fn pull_delta(
+    client: &mut SynchronizerClient<Channel>,
+    basis: &Path,
+    staged: Staged,
+    node: &Node,
+) -> Result<u64, Box<dyn Error>> {
+    let signature = signature(basis, &node.relative_path)?;
+    let mut patcher = Patcher::new(basis, staged, signature.block_size)?;
+    let block_size = signature.block_size;
+    let mut stream = client
+        .pull_delta(Request::new(signature))
+        .await?
+        .into_inner();
+    while let Some(delta) = stream.message().await? {
+        if delta.block_size != 0 && delta.block_size != block_size {
+            return Err(format!("deltas for block size {}", delta.block_size).into());
+        }
+        if let Some(op) = &delta.op {
+            patcher.apply(op)?;
+        }
+    }
+    Ok(patcher.commit(node)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::fingerprint;
+    use std::{env, fs};
+
+    // deterministic bytes that don't repeat within a block
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    // rebuild target from basis through signature, diff and patch,
+    // returning the rebuilt contents and the operations sent
+    fn round_trip(basis: &[u8], target: &[u8]) -> (Vec<u8>, Vec<Op>) {
+        let dir = env::temp_dir().join(format!("runison-delta-{:08x}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        let (basis_path, target_path, out) =
+            (dir.join("basis"), dir.join("target"), dir.join("out"));
+        fs::write(&basis_path, basis).unwrap();
+        fs::write(&target_path, target).unwrap();
+
+        let signature = signature(&basis_path, "target").unwrap();
+        let mut ops = Vec::new();
+        diff(&target_path, &signature, |op| {
+            ops.push(op);
+            true
+        })
+        .unwrap();
+        let mut patcher = Patcher::new(
+            &basis_path,
+            Staged::create(&out).unwrap(),
+            signature.block_size,
+        )
+        .unwrap();
+        for op in &ops {
+            patcher.apply(op).unwrap();
+        }
+        let node = Node {
+            len: target.len() as u64,
+            fingerprint: fingerprint(&target_path).unwrap(),
+            ..Default::default()
+        };
+        patcher.commit(&node).unwrap();
+        let rebuilt = fs::read(&out).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        (rebuilt, ops)
+    }
+
+    fn copies(ops: &[Op]) -> usize {
+        ops.iter().filter(|op| matches!(op, Op::Copy(_))).count()
+    }
+
+    #[test]
+    fn rolling_matches_fresh_checksum() {
+        let data = noise(5000, 1);
+        let window = 700;
+        let mut rolling = Rolling::new(&data[..window]);
+        for start in 1..data.len() - window {
+            rolling.roll(data[start - 1], data[start + window - 1]);
+            assert_eq!(
+                rolling.digest(),
+                Rolling::new(&data[start..start + window]).digest(),
+                "window at {}",
+                start
+            );
+        }
+    }
+
+    #[test]
+    fn identical() {
+        let basis = noise(100_000, 2);
+        let (rebuilt, ops) = round_trip(&basis, &basis);
+        assert_eq!(rebuilt, basis);
+        // every whole block is copied, only the tail is sent
+        assert_eq!(copies(&ops), basis.len() / 700);
+        assert_eq!(ops.len(), copies(&ops) + 1);
+    }
+
+    #[test]
+    fn inserts() {
+        let basis = noise(200_000, 3);
+        let mut target = noise(123, 4);
+        target.extend_from_slice(&basis[..90_000]);
+        target.extend_from_slice(&noise(5_000, 5));
+        target.extend_from_slice(&basis[90_000..]);
+        let (rebuilt, ops) = round_trip(&basis, &target);
+        assert_eq!(rebuilt, target);
+        assert!(copies(&ops) > 0);
+    }
+
+    #[test]
+    fn deletes() {
+        let basis = noise(200_000, 6);
+        let mut target = basis[17..60_000].to_vec();
+        target.extend_from_slice(&basis[75_321..]);
+        let (rebuilt, ops) = round_trip(&basis, &target);
+        assert_eq!(rebuilt, target);
+        assert!(copies(&ops) > 0);
+    }
+
+    #[test]
+    fn empty_basis() {
+        // longer than a chunk, so the literal data is sent in pieces
+        let target = noise(150_000, 7);
+        let (rebuilt, ops) = round_trip(&[], &target);
+        assert_eq!(rebuilt, target);
+        assert_eq!(copies(&ops), 0);
+    }
+
+    #[test]
+    fn empty_target() {
+        let (rebuilt, _) = round_trip(&noise(10_000, 8), &[]);
+        assert!(rebuilt.is_empty());
+    }
+
+    #[test]
+    fn tail_shorter_than_a_block() {
+        // neither length is a multiple of the block size
+        let basis = noise(700 * 10 + 333, 9);
+        let mut target = basis.clone();
+        target.extend_from_slice(&noise(100, 10));
+        let (rebuilt, ops) = round_trip(&basis, &target);
+        assert_eq!(rebuilt, target);
+        assert_eq!(copies(&ops), 10);
+        // a target shorter than a single block
+        let (rebuilt, _) = round_trip(&basis, &basis[..500]);
+        assert_eq!(rebuilt, &basis[..500]);
+    }
+
+    #[test]
+    fn rejects_bad_block_sizes() {
+        let dir = env::temp_dir().join(format!("runison-delta-{:08x}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        let (basis, out) = (dir.join("basis"), dir.join("out"));
+        fs::write(&basis, noise(10_000, 11)).unwrap();
+
+        let mut bad = signature(&basis, "basis").unwrap();
+        for &block_size in &[0, 699, 128 * 1024 + 1] {
+            bad.block_size = block_size;
+            let error = diff(&basis, &bad, |_| true).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+        // the basis grew past another block size since its signature
+        let error = Patcher::new(&basis, Staged::create(&out).unwrap(), 800)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // a copy past the end of the basis
+        let mut patcher = Patcher::new(&basis, Staged::create(&out).unwrap(), 700).unwrap();
+        patcher.apply(&Op::Copy(14)).unwrap();
+        let error = patcher.apply(&Op::Copy(15)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder, Glob};
+use ignore::Match;
+use regex::RegexSet;
+
+use crate::config::Config;
+use crate::node::escape;
+
+// per-directory file listing paths to leave out of synchronization
+pub const IGNORE_FILE: &str = ".runisonignore";
+
+// decides which paths of a replica are left out of synchronization,
+// following the rules of gitignore. the [ignore] patterns apply to the
+// whole root, and the ignore files of a directory to what is below it
+pub struct Filter {
+    root: PathBuf,
+    // [ignore] name and path patterns, compiled once
+    config: Gitignore,
+    // [ignore] regex patterns, matching whole relative paths
+    regex: RegexSet,
+    regex_patterns: Vec<String>,
+    // [ignore] not patterns, including paths whatever else matches them
+    not: Gitignore,
+    not_regex: RegexSet,
+    not_regex_patterns: Vec<String>,
+    // also honor .gitignore files
+    gitignore: bool,
+    // compiled ignore files of each directory checked since the last
+    // reload. the walk reads them from many threads at once
+    directories: RwLock<HashMap<PathBuf, Arc<Gitignore>>>,
+}
+
+// whether a rule leaves a path out of synchronization, and which rule
+pub struct Decision {
+    pub ignored: bool,
+    pub rule: String,
+}
+
+impl Filter {
+    pub fn new(config: &Config) -> Result<Filter, Box<dyn Error>> {
+        let root = PathBuf::from(&config.root.path);
+        let ignore = &config.ignore;
+        let mut builder = GitignoreBuilder::new(&root);
+        for pattern in ignore.name.iter().chain(ignore.path.iter()) {
+            builder.add_line(None, pattern)?;
+        }
+        let mut not = GitignoreBuilder::new(&root);
+        for pattern in &ignore.not {
+            not.add_line(None, pattern)?;
+        }
+        Ok(Filter {
+            config: builder.build()?,
+            regex: anchored(&ignore.regex)?,
+            regex_patterns: ignore.regex.clone(),
+            not: not.build()?,
+            not_regex: anchored(&ignore.not_regex)?,
+            not_regex_patterns: ignore.not_regex.clone(),
+            root,
+            gitignore: ignore.gitignore,
+            directories: RwLock::new(HashMap::new()),
+        })
+    }
+    // forget the ignore files read so far, so that changes
+    // to them are picked up by the next index
+    pub fn reload(&self) {
+        self.directories
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+    // returns true if a path below the root is left out of
+    // synchronization. the root itself never is
+    pub fn ignored(&self, path: &Path, is_dir: bool) -> bool {
+        self.decide(path, is_dir).map_or(false, |d| d.ignored)
+    }
+    // the rule deciding whether a path is left out of synchronization,
+    // None if no rule matches it. this doesn't look at the directories
+    // above the path, which the index never walks into if they are ignored
+    pub fn decide(&self, path: &Path, is_dir: bool) -> Option<Decision> {
+        if path == self.root {
+            return None;
+        }
+        // archives and temporary files of runison itself
+        if let Some(name) = path.file_name() {
+            if name.to_string_lossy().starts_with(".runison-") {
+                return Some(Decision {
+                    ignored: true,
+                    rule: String::from("runison's own files"),
+                });
+            }
+        }
+        // regexes match the escaped path, the way it is shown
+        let relative_path = escape(path.strip_prefix(&self.root).unwrap_or(path).as_os_str());
+        // exceptions win over every other rule
+        if let Match::Ignore(glob) = self.not.matched(path, is_dir) {
+            return Some(included(describe(glob, "[ignore] not")));
+        }
+        if let Some(i) = self.not_regex.matches(&relative_path).iter().next() {
+            let rule = format!("`{}` in [ignore] not_regex", self.not_regex_patterns[i]);
+            return Some(included(rule));
+        }
+        // the ignore file closest to a path has the last word
+        for dir in path.ancestors().skip(1) {
+            if let Some(decision) = decision(self.directory(dir).matched(path, is_dir), "") {
+                return Some(decision);
+            }
+            if dir == self.root {
+                break;
+            }
+        }
+        if let Some(decision) = decision(self.config.matched(path, is_dir), "[ignore]") {
+            return Some(decision);
+        }
+        self.regex
+            .matches(&relative_path)
+            .iter()
+            .next()
+            .map(|i| Decision {
+                ignored: true,
+                rule: format!("`{}` in [ignore] regex", self.regex_patterns[i]),
+            })
+    }
+    // a sentence explaining whether a path below the root is left out
+    // of synchronization, and why. unlike decide, this accounts for
+    // ignored directories above the path
+    pub fn explain(&self, path: &Path) -> String {
+        let relative_path = path.strip_prefix(&self.root).unwrap_or(path);
+        let shown = escape(relative_path.as_os_str());
+        let mut dir = self.root.clone();
+        if let Some(parent) = relative_path.parent() {
+            for component in parent.components() {
+                dir.push(component);
+                match self.decide(&dir, true) {
+                    Some(decision) if decision.ignored => {
+                        return format!(
+                            "{}: ignored, its directory {} is ignored by {}",
+                            shown,
+                            escape(dir.strip_prefix(&self.root).unwrap_or(&dir).as_os_str()),
+                            decision.rule
+                        )
+                    }
+                    _ => {}
+                }
+            }
+        }
+        match self.decide(path, path.is_dir()) {
+            Some(decision) if decision.ignored => {
+                format!("{}: ignored by {}", shown, decision.rule)
+            }
+            Some(decision) => format!("{}: included by {}", shown, decision.rule),
+            None => format!("{}: not ignored", shown),
+        }
+    }
+    // the patterns of the ignore files in a directory
+    fn directory(&self, dir: &Path) -> Arc<Gitignore> {
+        let directories = self.directories.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(matcher) = directories.get(dir) {
+            return Arc::clone(matcher);
+        }
+        drop(directories);
+        // the files are read without holding the lock, and should two
+        // threads both read them, the first matcher stored is kept
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut names = vec![IGNORE_FILE];
+        if self.gitignore {
+            // .runisonignore comes last so it overrides .gitignore
+            names.insert(0, ".gitignore");
+        }
+        for name in names {
+            let file = dir.join(name);
+            if file.is_file() {
+                if let Some(e) = builder.add(&file) {
+                    eprintln!("Error reading {:?}: {}", file, e);
+                }
+            }
+        }
+        let matcher = builder.build().unwrap_or_else(|e| {
+            eprintln!("Error reading ignore files in {:?}: {}", dir, e);
+            Gitignore::empty()
+        });
+        let mut directories = self.directories.write().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(
+            directories
+                .entry(dir.to_path_buf())
+                .or_insert_with(|| Arc::new(matcher)),
+        )
+    }
+}
+
+// regex patterns that have to match a whole relative path
+fn anchored(patterns: &[String]) -> Result<RegexSet, regex::Error> {
+    RegexSet::new(patterns.iter().map(|p| format!("^(?:{})$", p)))
+}
+
+fn included(rule: String) -> Decision {
+    Decision {
+        ignored: false,
+        rule,
+    }
+}
+
+fn decision(matched: Match<&Glob>, section: &str) -> Option<Decision> {
+    match matched {
+        Match::Ignore(glob) => Some(Decision {
+            ignored: true,
+            rule: describe(glob, section),
+        }),
+        Match::Whitelist(glob) => Some(included(describe(glob, section))),
+        Match::None => None,
+    }
+}
+
+// a pattern and where it comes from, a file or a config section
+fn describe(glob: &Glob, section: &str) -> String {
+    match glob.from() {
+        Some(file) => format!("`{}` in {}", glob.original(), file.display()),
+        None => format!("`{}` in {}", glob.original(), section),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+
+    // a filter for a root that doesn't exist, so no ignore files
+    // take part, from the [ignore] section given
+    fn filter(ignore: &str) -> Filter {
+        let config: Config = Figment::new()
+            .merge(Toml::string(&format!(
+                "[root]\npath = \"/nonexistent/root\"\n[ignore]\n{}",
+                ignore
+            )))
+            .extract()
+            .unwrap();
+        Filter::new(&config).unwrap()
+    }
+
+    fn ignored(filter: &Filter, path: &str, is_dir: bool) -> bool {
+        filter.ignored(&Path::new("/nonexistent/root").join(path), is_dir)
+    }
+
+    #[test]
+    fn negation() {
+        let filter = filter(r#"name = ["*.log", "!keep.log"]"#);
+        assert!(ignored(&filter, "a.log", false));
+        assert!(ignored(&filter, "dir/a.log", false));
+        assert!(!ignored(&filter, "keep.log", false));
+        assert!(!ignored(&filter, "dir/keep.log", false));
+    }
+
+    #[test]
+    fn anchoring() {
+        let filter = filter(r#"path = ["/build", "docs/*.pdf"]"#);
+        assert!(ignored(&filter, "build", true));
+        assert!(!ignored(&filter, "src/build", true));
+        assert!(ignored(&filter, "docs/a.pdf", false));
+        assert!(!ignored(&filter, "src/docs/a.pdf", false));
+        // a pattern without a slash matches at any depth
+        let filter = self::filter(r#"name = ["build"]"#);
+        assert!(ignored(&filter, "src/build", true));
+    }
+
+    #[test]
+    fn directory_patterns() {
+        let filter = filter(r#"name = ["out/"]"#);
+        assert!(ignored(&filter, "out", true));
+        assert!(ignored(&filter, "src/out", true));
+        assert!(!ignored(&filter, "out", false));
+    }
+
+    #[test]
+    fn not() {
+        let filter = filter(
+            r#"name = ["*.bak"]
+not = ["important.bak"]"#,
+        );
+        assert!(ignored(&filter, "a.bak", false));
+        let decision = filter
+            .decide(Path::new("/nonexistent/root/important.bak"), false)
+            .unwrap();
+        assert!(!decision.ignored);
+        assert_eq!(decision.rule, "`important.bak` in [ignore] not");
+    }
+
+    #[test]
+    fn not_beats_regex() {
+        let filter = filter(
+            r#"regex = ["data/.*"]
+not = ["data/keep"]
+not_regex = ["data/also[0-9]"]"#,
+        );
+        assert!(ignored(&filter, "data/x", false));
+        assert!(!ignored(&filter, "data/keep", false));
+        assert!(!ignored(&filter, "data/also1", false));
+        // regexes match the whole path
+        assert!(!ignored(&filter, "more/data/x", false));
+    }
+
+    #[test]
+    fn own_files() {
+        let filter = filter("");
+        assert!(ignored(&filter, ".runison-current", false));
+        assert!(ignored(&filter, "dir/.runison-tmp-a-0000", false));
+        assert!(!ignored(&filter, "", true));
+    }
+}
@@ -1,10 +1,21 @@
-use crate::config::{Config, Path};
+use crate::config::{Attributes, Config, Path};
 use crate::runison::*;
+use crate::transfer::data_extents;
 
+use filetime::FileTime;
 use serde::{Deserialize, Serialize};
-use std::os::unix::fs::MetadataExt;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
-use std::{ffi::OsString, time::SystemTime};
+use std::{
+    ffi::{OsStr, OsString},
+    time::SystemTime,
+};
+use xattr::FileExt;
 /*
 pub struct Node {
     pub is_dir: bool,
@@ -24,14 +35,15 @@ impl Node {
         dir: bool,
         file: bool,
         symlink: bool,
-        name: String,
-        path: String,
+        name: Vec<u8>,
+        path: Vec<u8>,
         relative_path: String,
         len: u64,
         mod_seconds: u64,
         mod_nano: u32,
         inode: u64,
-        root_path: String,
+        root_path: Vec<u8>,
+        fingerprint: Vec<u8>,
     ) -> Option<Node> {
         Some(Node {
             dir,
@@ -45,13 +57,12 @@ impl Node {
             mod_nano,
             inode,
             root_path,
+            fingerprint,
+            ..Default::default()
         })
     }
     pub fn from_path(root_path: PathBuf, path: PathBuf, config: &Config) -> Option<Node> {
         // add the root path back to the given path to get the full file path
-        let config = config.clone();
-        // get the metadata of the file
-        // TODO: handle error for bad symlinks
         let mut joined = PathBuf::new();
         for p in root_path.iter() {
             joined.push(p);
@@ -62,17 +73,39 @@ impl Node {
                 joined.push(p);
             }
         }
-        let metadata = std::fs::metadata(&joined).unwrap();
+        // symlinks are recorded as links unless they are followed, and
+        // dangling ones or ones leading out of the root always are
+        let link = std::fs::symlink_metadata(&joined).ok()?;
+        let metadata =
+            if config.links.follow && link.file_type().is_symlink() && follows(&root_path, &joined)
+            {
+                std::fs::metadata(&joined).unwrap_or(link)
+            } else {
+                link
+            };
+        let link_target = if metadata.file_type().is_symlink() {
+            fs::read_link(&joined).ok()?.into_os_string().into_vec()
+        } else {
+            Vec::new()
+        };
         let inode = metadata.ino();
         let filetype = metadata.file_type();
+        // only the attributes that are synchronized are recorded
+        let attributes = &config.attributes;
+        let sparse = filetype.is_file() && config.transfer.sparse && is_sparse(&joined, &metadata);
+        let xattrs = if filetype.is_symlink() {
+            HashMap::new()
+        } else {
+            xattrs(&joined, &attributes.xattrs)
+        };
 
         let root = Node {
             dir: filetype.is_dir(),
             file: filetype.is_file(),
             symlink: filetype.is_symlink(),
-            name: String::from(path.clone().into_os_string().to_str().unwrap()),
-            path: String::from(joined.into_os_string().to_str().unwrap()),
-            relative_path: String::from(path.clone().into_os_string().to_str().unwrap()),
+            name: path.as_os_str().as_bytes().to_vec(),
+            path: joined.into_os_string().into_vec(),
+            relative_path: escape(path.as_os_str()),
             len: metadata.len(),
             mod_seconds: match metadata
                 .modified()
@@ -91,8 +124,395 @@ impl Node {
                 Err(_) => 0,
             },
             inode,
-            root_path: String::from(root_path.into_os_string().to_str().unwrap()),
+            root_path: root_path.into_os_string().into_vec(),
+            fingerprint: Vec::new(),
+            mode: if attributes.permissions {
+                metadata.mode()
+            } else {
+                0
+            },
+            owner: if attributes.owner {
+                Some(Owner {
+                    uid: metadata.uid(),
+                    gid: metadata.gid(),
+                })
+            } else {
+                None
+            },
+            access_seconds: if attributes.atime {
+                metadata.atime() as u64
+            } else {
+                0
+            },
+            access_nano: if attributes.atime {
+                metadata.atime_nsec() as u32
+            } else {
+                0
+            },
+            link_target,
+            xattrs,
+            device: metadata.dev(),
+            hard_link: String::new(),
+            sparse,
         };
         Some(root)
     }
+    // returns true if the node has been updated since the
+    // earlier snapshot of the same path was taken
+    pub fn differs_from(&self, earlier: &Node) -> bool {
+        // a change of type, e.g. a file replaced by a directory
+        if self.dir != earlier.dir || self.file != earlier.file || self.symlink != earlier.symlink {
+            return true;
+        }
+        if !self.same_attributes(earlier) {
+            return true;
+        }
+        if self.symlink {
+            return self.link_target != earlier.link_target;
+        }
+        if self.hard_link != earlier.hard_link {
+            return true;
+        }
+        // directories are only updated by being added, deleted
+        // or getting new attributes
+        if self.dir {
+            return false;
+        }
+        if self.len != earlier.len {
+            return true;
+        }
+        // compare contents when both sides have been hashed, so a file
+        // that was only touched doesn't count as updated
+        if !self.fingerprint.is_empty() && !earlier.fingerprint.is_empty() {
+            return self.fingerprint != earlier.fingerprint;
+        }
+        self.mod_seconds != earlier.mod_seconds || self.mod_nano != earlier.mod_nano
+    }
+    // returns true if two nodes carry the same permissions, owner and
+    // extended attributes.
+    // attributes that only one of them recorded are not compared
+    pub fn same_attributes(&self, other: &Node) -> bool {
+        let permissions = self.mode == 0 || other.mode == 0 || self.mode == other.mode;
+        let owner = match (&self.owner, &other.owner) {
+            (Some(owner), Some(other)) => owner == other,
+            _ => true,
+        };
+        permissions && owner && self.xattrs == other.xattrs
+    }
+    // returns true if this is a symlink pointing outside of the root,
+    // either by an absolute path or by climbing out with ..
+    pub fn escapes_root(&self) -> bool {
+        if !self.symlink {
+            return false;
+        }
+        let target = std::path::Path::new(OsStr::from_bytes(&self.link_target));
+        if target.is_absolute() {
+            return true;
+        }
+        // the link resolves relative to the directory it is in
+        let mut depth = std::path::Path::new(&unescape(&self.relative_path))
+            .components()
+            .filter(|c| matches!(c, std::path::Component::Normal(_)))
+            .count() as i64
+            - 1;
+        for component in target.components() {
+            match component {
+                std::path::Component::ParentDir => depth -= 1,
+                std::path::Component::Normal(_) => depth += 1,
+                _ => {}
+            }
+            if depth < 0 {
+                return true;
+            }
+        }
+        false
+    }
+    // give the local copy of this node the attributes recorded for it,
+    // as far as the configuration synchronizes them
+    pub fn apply_attributes(
+        &self,
+        path: &std::path::Path,
+        attributes: &Attributes,
+    ) -> io::Result<()> {
+        // setting attributes would follow the link to its target
+        if self.symlink {
+            return Ok(());
+        }
+        // whatever the node says, the attributes are set through a
+        // descriptor of the path itself, which can't be a link
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+            .open(path)
+            .map_err(|e| match e.raw_os_error() {
+                Some(libc::ELOOP) => io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is a symlink", self.relative_path),
+                ),
+                _ => e,
+            })?;
+        // before the permissions, as a new owner clears setuid bits.
+        // only root may give files away
+        if let (true, Some(owner)) = (attributes.owner, &self.owner) {
+            if unsafe { libc::geteuid() } == 0
+                && unsafe { libc::fchown(file.as_raw_fd(), owner.uid, owner.gid) } != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if attributes.permissions && self.mode != 0 {
+            let mut mode = self.mode & 0o7777;
+            if self.dir {
+                // keep directories writable, so later runs can
+                // still synchronize their contents
+                mode |= 0o700;
+            }
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+        }
+        // after the permissions, as an ACL also sets the group bits
+        if !attributes.xattrs.is_empty() {
+            let current = read_xattrs(
+                &attributes.xattrs,
+                || file.list_xattr(),
+                |name| file.get_xattr(name),
+            );
+            for name in current.keys() {
+                if !self.xattrs.contains_key(name) {
+                    file.remove_xattr(name)?;
+                }
+            }
+            for (name, value) in &self.xattrs {
+                let synchronized = attributes.xattrs.iter().any(|p| name.starts_with(p));
+                if synchronized && current.get(name) != Some(value) {
+                    file.set_xattr(name, value)?;
+                }
+            }
+        }
+        // the times of directories change with their contents anyway
+        if self.file && (attributes.mtime || attributes.atime) {
+            let metadata = file.metadata()?;
+            let mtime = if attributes.mtime {
+                FileTime::from_unix_time(self.mod_seconds as i64, self.mod_nano)
+            } else {
+                FileTime::from_last_modification_time(&metadata)
+            };
+            let atime = if attributes.atime && self.access_seconds != 0 {
+                FileTime::from_unix_time(self.access_seconds as i64, self.access_nano)
+            } else {
+                FileTime::from_last_access_time(&metadata)
+            };
+            filetime::set_file_handle_times(&file, Some(atime), Some(mtime))?;
+        }
+        Ok(())
+    }
+}
+
+// the extended attributes of a path whose names start with one of
+// the given prefixes. a file system without them has none
+fn xattrs(path: &std::path::Path, prefixes: &[String]) -> HashMap<String, Vec<u8>> {
+    read_xattrs(
+        prefixes,
+        || xattr::list(path),
+        |name| xattr::get(path, name),
+    )
+}
+
+// the same, listing the names of the attributes with list and reading
+// each one with get, from a path or from an open file
+fn read_xattrs<L, G>(prefixes: &[String], list: L, get: G) -> HashMap<String, Vec<u8>>
+where
+    L: FnOnce() -> io::Result<xattr::XAttrs>,
+    G: Fn(&str) -> io::Result<Option<Vec<u8>>>,
+{
+    let mut xattrs = HashMap::new();
+    if prefixes.is_empty() {
+        return xattrs;
+    }
+    let names = match list() {
+        Ok(names) => names,
+        Err(_) => return xattrs,
+    };
+    for name in names {
+        let name = name.to_string_lossy().into_owned();
+        if !prefixes.iter().any(|p| name.starts_with(p)) {
+            continue;
+        }
+        if let Ok(Some(value)) = get(&name) {
+            xattrs.insert(name, value);
+        }
+    }
+    xattrs
+}
+
+// returns true if a symlink may be followed: only links leading to an
+// existing path inside the root are, so nothing outside of it is ever
+// read or written through one
+pub fn follows(root: &std::path::Path, link: &std::path::Path) -> bool {
+    match (fs::canonicalize(root), fs::canonicalize(link)) {
+        (Ok(root), Ok(target)) => target.starts_with(root),
+        _ => false,
+    }
+}
+
+// a path relative to the root as it appears in Entries and requests.
+// UTF-8 is kept as it is, except for backslashes, which are doubled.
+// control characters and other bytes become \xNN, so unescape gets
+// back every path exactly, and printing one is always safe
+pub fn escape(path: &OsStr) -> String {
+    let mut escaped = String::new();
+    let mut bytes = path.as_bytes();
+    while !bytes.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(bytes) {
+            Ok(valid) => (valid, 0),
+            Err(e) => (
+                std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+                e.error_len().unwrap_or(bytes.len() - e.valid_up_to()),
+            ),
+        };
+        for c in valid.chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                c if c.is_control() => {
+                    for b in c.to_string().bytes() {
+                        escaped.push_str(&format!("\\x{:02x}", b));
+                    }
+                }
+                c => escaped.push(c),
+            }
+        }
+        for b in &bytes[valid.len()..valid.len() + invalid] {
+            escaped.push_str(&format!("\\x{:02x}", b));
+        }
+        bytes = &bytes[valid.len() + invalid..];
+    }
+    escaped
+}
+
+// the file system path of an escaped relative path. a backslash that
+// starts no escape stands for itself
+pub fn unescape(path: &str) -> OsString {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'\\' {
+            if tail.first() == Some(&b'\\') {
+                bytes.push(b'\\');
+                rest = &tail[1..];
+                continue;
+            }
+            if tail.len() >= 3 && tail[0] == b'x' {
+                let byte = std::str::from_utf8(&tail[1..3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                if let Some(byte) = byte {
+                    bytes.push(byte);
+                    rest = &tail[3..];
+                    continue;
+                }
+            }
+        }
+        bytes.push(b);
+        rest = tail;
+    }
+    OsString::from_vec(bytes)
+}
+
+// returns true if a file has holes, i.e. less data than its length
+fn is_sparse(path: &std::path::Path, metadata: &fs::Metadata) -> bool {
+    // fewer allocated blocks than the length needs is a cheap hint
+    if metadata.blocks() * 512 >= metadata.len() {
+        return false;
+    }
+    File::open(path)
+        .and_then(|file| data_extents(&file, metadata.len()))
+        .map(|extents| extents.iter().map(|(_, len)| len).sum::<u64>() < metadata.len())
+        .unwrap_or(false)
+}
+
+// BLAKE3 hash of the contents of a file
+pub fn fingerprint<P: AsRef<std::path::Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bytes: &[u8]) -> String {
+        let escaped = escape(OsStr::from_bytes(bytes));
+        assert_eq!(unescape(&escaped).as_bytes(), bytes, "{}", escaped);
+        escaped
+    }
+
+    #[test]
+    fn utf8_is_kept() {
+        assert_eq!(
+            round_trip("dir/résumé ✓.txt".as_bytes()),
+            "dir/résumé ✓.txt"
+        );
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        assert_eq!(round_trip(b"a\xff\xfeb"), "a\\xff\\xfeb");
+        assert_eq!(round_trip(b"a\xffb"), "a\\xffb");
+        assert_eq!(round_trip(b"\xe2\x82"), "\\xe2\\x82");
+    }
+
+    #[test]
+    fn control_characters() {
+        assert_eq!(
+            round_trip(b"new\nline\ttab\x7f"),
+            "new\\x0aline\\x09tab\\x7f"
+        );
+    }
+
+    #[test]
+    fn literal_escape() {
+        // a name that reads like an escape isn't one
+        assert_eq!(round_trip(b"\\x41"), "\\\\x41");
+        assert_eq!(round_trip(b"\\\\x41"), "\\\\\\\\x41");
+    }
+
+    #[test]
+    fn trailing_backslashes() {
+        assert_eq!(round_trip(b"dir\\"), "dir\\\\");
+        assert_eq!(round_trip(b"dir\\\\"), "dir\\\\\\\\");
+        assert_eq!(round_trip(b"\\"), "\\\\");
+    }
+
+    #[test]
+    fn non_canonical_escapes() {
+        // paths that unescape to a name whose escaped form differs
+        for path in &["\\x41", "a\\b", "\\x0A", "a\\", "new\nline"] {
+            assert_ne!(escape(&unescape(path)), *path, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn attributes_never_follow_links() {
+        let dir = std::env::temp_dir().join(format!("runison-node-{:08x}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        let (target, link) = (dir.join("target"), dir.join("link"));
+        fs::write(&target, b"outside").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o644)).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        // a node claiming the link is a plain file
+        let node = Node {
+            file: true,
+            relative_path: String::from("link"),
+            mode: 0o100600,
+            len: 7,
+            ..Default::default()
+        };
+        assert!(node
+            .apply_attributes(&link, &Attributes::default())
+            .is_err());
+        let mode = fs::metadata(&target).unwrap().mode() & 0o7777;
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(mode, 0o644);
+    }
 }
@@ -0,0 +1,486 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config::ConflictPolicy;
+use crate::runison::*;
+
+// State of one replica as seen by the reconciler
+pub struct Replica<'a> {
+    // state recorded at the last synchronization, None on the first run
+    pub archive: Option<&'a Entries>,
+    // state of the replica right now
+    pub current: &'a Entries,
+    // how its file system treats names
+    pub names: Names,
+}
+
+impl<'a> Replica<'a> {
+    // returns true if the path was updated since the archive was written.
+    // without an archive, every path that exists counts as updated
+    fn updated(&self, path: &str) -> bool {
+        let current = self.current.nodes.get(path);
+        let archive = match self.archive {
+            Some(archive) => archive,
+            None => return current.is_some(),
+        };
+        match (archive.nodes.get(path), current) {
+            (None, None) => false,
+            (Some(archived), Some(current)) => current.differs_from(archived),
+            _ => true,
+        }
+    }
+}
+
+// compares each replica against its own archive and classifies
+// every path known to either side
+pub fn reconcile(client: &Replica, server: &Replica) -> Plan {
+    // sorted, so parent directories come before their contents
+    let mut paths = BTreeSet::new();
+    for replica in [client, server].iter() {
+        paths.extend(replica.current.nodes.keys());
+        if let Some(archive) = replica.archive {
+            paths.extend(archive.nodes.keys());
+        }
+    }
+
+    let mut items = Vec::new();
+    for path in paths.iter() {
+        let left = client.current.nodes.get(*path);
+        let right = server.current.nodes.get(*path);
+        let action = match (client.updated(path), server.updated(path)) {
+            (false, false) => Action::Unchanged,
+            // nothing needs to be transferred: the archives written on
+            // commit record the current, identical state of both replicas
+            (true, true) if same_contents(left, right) => Action::FalseConflict,
+            // updated on one side to what the other side already holds
+            _ if same_contents(left, right) => Action::Unchanged,
+            (true, false) => Action::LeftToRight,
+            (false, true) => Action::RightToLeft,
+            (true, true) => Action::Conflict,
+        };
+        // deleting a directory, or replacing it with a file or link,
+        // would take along whatever the other replica updated below it
+        let action = match action {
+            Action::LeftToRight
+                if left.map_or(true, |n| !n.dir)
+                    && right.map_or(false, |n| n.dir)
+                    && updated_below(server, path, &paths) =>
+            {
+                Action::Conflict
+            }
+            Action::RightToLeft
+                if right.map_or(true, |n| !n.dir)
+                    && left.map_or(false, |n| n.dir)
+                    && updated_below(client, path, &paths) =>
+            {
+                Action::Conflict
+            }
+            action => action,
+        };
+        items.push(PlanItem {
+            relative_path: (*path).clone(),
+            action: action as i32,
+            client: left.cloned(),
+            server: right.cloned(),
+            collides_with: String::new(),
+        });
+    }
+    mark_collisions(&mut items, client, server);
+    Plan { items }
+}
+
+impl Names {
+    // the name a path has on a file system treating names this way
+    fn fold(&self, path: &str) -> String {
+        let folded: String = if self.normalization {
+            path.nfc().collect()
+        } else {
+            path.to_string()
+        };
+        if self.case_insensitive {
+            folded.to_lowercase()
+        } else {
+            folded
+        }
+    }
+}
+
+// find paths that exist on either replica but would be the same file on
+// a replica that ignores case or normalizes names, and leave them and
+// everything below them out of synchronization instead of merging them
+fn mark_collisions(items: &mut [PlanItem], client: &Replica, server: &Replica) {
+    let mut colliding: BTreeMap<String, String> = BTreeMap::new();
+    for names in [&client.names, &server.names].iter() {
+        if !names.case_insensitive && !names.normalization {
+            continue;
+        }
+        let mut folded: BTreeMap<String, &String> = BTreeMap::new();
+        for item in items.iter() {
+            if item.client.is_none() && item.server.is_none() {
+                continue;
+            }
+            let path = &item.relative_path;
+            if let Some(other) = folded.insert(names.fold(path), path) {
+                colliding.insert(path.clone(), other.clone());
+                colliding
+                    .entry(other.clone())
+                    .or_insert_with(|| path.clone());
+            }
+        }
+    }
+    if colliding.is_empty() {
+        return;
+    }
+    for item in items.iter_mut() {
+        let path = &item.relative_path;
+        let other = colliding.get(path).or_else(|| {
+            path.match_indices('/')
+                .find_map(|(i, _)| colliding.get(&path[..i]))
+        });
+        if let Some(other) = other {
+            item.collides_with = other.clone();
+            item.action = Action::Collision as i32;
+        }
+    }
+}
+
+// returns true if a replica updated any path below a directory
+fn updated_below(replica: &Replica, dir: &str, paths: &BTreeSet<&String>) -> bool {
+    let prefix = format!("{}/", dir);
+    paths
+        .range::<String, _>(prefix.clone()..)
+        .take_while(|path| path.starts_with(&prefix))
+        .any(|path| replica.updated(path))
+}
+
+// returns true if both replicas hold the same thing at a path,
+// attributes included
+fn same_contents(left: Option<&Node>, right: Option<&Node>) -> bool {
+    match (left, right) {
+        (None, None) => true,
+        (Some(left), Some(right)) => same_data(left, right) && left.same_attributes(right),
+        _ => false,
+    }
+}
+
+// returns true if two nodes only differ in their attributes, if at all
+fn same_data(left: &Node, right: &Node) -> bool {
+    if left.dir || right.dir {
+        return left.dir && right.dir;
+    }
+    if left.symlink || right.symlink {
+        return left.symlink && right.symlink && left.link_target == right.link_target;
+    }
+    left.file
+        && right.file
+        && left.len == right.len
+        && !left.fingerprint.is_empty()
+        && left.fingerprint == right.fingerprint
+}
+
+impl Plan {
+    // the changes carrying out the default action of every item
+    pub fn changes(&self) -> Vec<Change> {
+        self.items
+            .iter()
+            .filter_map(|item| item.change(item.action()))
+            .collect()
+    }
+}
+
+impl PlanItem {
+    // returns true if both replicas already agree on this path
+    pub fn in_sync(&self) -> bool {
+        match self.action() {
+            Action::Unchanged | Action::FalseConflict => true,
+            _ => false,
+        }
+    }
+    // the suggested action for this path, None if there is
+    // nothing to propagate or the path is conflicting
+    pub fn default_action(&self) -> Option<Action> {
+        match self.action() {
+            Action::LeftToRight => Some(Action::LeftToRight),
+            Action::RightToLeft => Some(Action::RightToLeft),
+            _ => None,
+        }
+    }
+    // the action a conflict policy picks for this path, None if it
+    // stays unresolved. a deletion never wins over a copy that still
+    // exists unless the policy prefers that side outright
+    pub fn resolve(&self, policy: ConflictPolicy) -> Option<Action> {
+        match (policy, &self.client, &self.server) {
+            (ConflictPolicy::Skip, _, _) => None,
+            (ConflictPolicy::PreferClient, _, _) => Some(Action::LeftToRight),
+            (ConflictPolicy::PreferServer, _, _) => Some(Action::RightToLeft),
+            (ConflictPolicy::PreferNewer, Some(client), Some(server)) => {
+                let client_time = (client.mod_seconds, client.mod_nano);
+                let server_time = (server.mod_seconds, server.mod_nano);
+                if client_time > server_time {
+                    Some(Action::LeftToRight)
+                } else if server_time > client_time {
+                    Some(Action::RightToLeft)
+                } else {
+                    None
+                }
+            }
+            // keeping both copies of two files needs a transfer of its own
+            (ConflictPolicy::KeepBoth, Some(_), Some(_)) => None,
+            (_, Some(_), None) => Some(Action::LeftToRight),
+            (_, None, Some(_)) => Some(Action::RightToLeft),
+            (_, None, None) => None,
+        }
+    }
+    // returns true if a conflict policy keeps both copies of this path
+    pub fn keeps_both(&self, policy: ConflictPolicy) -> bool {
+        match (&self.client, &self.server) {
+            (Some(client), Some(server)) => {
+                policy == ConflictPolicy::KeepBoth
+                    && self.action() == Action::Conflict
+                    && client.file
+                    && server.file
+            }
+            _ => false,
+        }
+    }
+    // the change that propagates this path in the direction of
+    // the given action, or None if the action propagates nothing
+    pub fn change(&self, action: Action) -> Option<Change> {
+        // a collision is never propagated, whatever is chosen for it
+        if self.action() == Action::Collision {
+            return None;
+        }
+        let (change_type, node) = match action {
+            Action::LeftToRight => match (&self.client, &self.server) {
+                (Some(client), None) => (ChangeType::Clientadd, client),
+                (Some(client), Some(server)) if same_data(client, server) => {
+                    (ChangeType::Clientmetadata, client)
+                }
+                (Some(client), Some(_)) => (ChangeType::Clientmodify, client),
+                (None, Some(server)) => (ChangeType::Clientdelete, server),
+                (None, None) => return None,
+            },
+            Action::RightToLeft => match (&self.client, &self.server) {
+                (None, Some(server)) => (ChangeType::Serveradd, server),
+                (Some(client), Some(server)) if same_data(client, server) => {
+                    (ChangeType::Servermetadata, server)
+                }
+                (Some(_), Some(server)) => (ChangeType::Servermodify, server),
+                (Some(client), None) => (ChangeType::Serverdelete, client),
+                (None, None) => return None,
+            },
+            _ => return None,
+        };
+        Some(Change {
+            change_type: change_type as i32,
+            node: Some(node.clone()),
+            target: String::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, contents: &str) -> Node {
+        Node {
+            file: true,
+            relative_path: path.to_string(),
+            len: contents.len() as u64,
+            fingerprint: blake3::hash(contents.as_bytes()).as_bytes().to_vec(),
+            mode: 0o100644,
+            ..Default::default()
+        }
+    }
+
+    fn dir(path: &str) -> Node {
+        Node {
+            dir: true,
+            relative_path: path.to_string(),
+            mode: 0o40755,
+            ..Default::default()
+        }
+    }
+
+    fn entries(nodes: &[Node]) -> Entries {
+        Entries {
+            nodes: nodes
+                .iter()
+                .map(|node| (node.relative_path.clone(), node.clone()))
+                .collect(),
+        }
+    }
+
+    // reconcile two replicas that name files alike, with the same
+    // archive on both sides, or none
+    fn reconciled(archive: Option<&[Node]>, client: &[Node], server: &[Node]) -> Plan {
+        let archive = archive.map(entries);
+        let (client, server) = (entries(client), entries(server));
+        reconcile(
+            &Replica {
+                archive: archive.as_ref(),
+                current: &client,
+                names: Names::default(),
+            },
+            &Replica {
+                archive: archive.as_ref(),
+                current: &server,
+                names: Names::default(),
+            },
+        )
+    }
+
+    fn item<'a>(plan: &'a Plan, path: &str) -> &'a PlanItem {
+        plan.items
+            .iter()
+            .find(|item| item.relative_path == path)
+            .unwrap()
+    }
+
+    fn action(plan: &Plan, path: &str) -> Action {
+        item(plan, path).action()
+    }
+
+    #[test]
+    fn first_run_without_archive() {
+        let plan = reconciled(
+            None,
+            &[file("client", "a"), file("same", "s"), file("both", "c")],
+            &[file("server", "b"), file("same", "s"), file("both", "d")],
+        );
+        assert_eq!(action(&plan, "client"), Action::LeftToRight);
+        assert_eq!(action(&plan, "server"), Action::RightToLeft);
+        assert_eq!(action(&plan, "same"), Action::FalseConflict);
+        assert_eq!(action(&plan, "both"), Action::Conflict);
+    }
+
+    #[test]
+    fn deletion_against_modification() {
+        let archive = [file("modified", "old"), file("untouched", "old")];
+        let plan = reconciled(
+            Some(&archive),
+            &[],
+            &[file("modified", "new"), file("untouched", "old")],
+        );
+        assert_eq!(action(&plan, "modified"), Action::Conflict);
+        assert_eq!(action(&plan, "untouched"), Action::LeftToRight);
+        let change = item(&plan, "untouched")
+            .change(Action::LeftToRight)
+            .unwrap();
+        assert_eq!(change.change_type(), ChangeType::Clientdelete);
+    }
+
+    #[test]
+    fn false_conflict() {
+        let plan = reconciled(
+            Some(&[file("f", "old")]),
+            &[file("f", "new")],
+            &[file("f", "new")],
+        );
+        assert_eq!(action(&plan, "f"), Action::FalseConflict);
+        assert!(item(&plan, "f").in_sync());
+        assert!(plan.changes().is_empty());
+    }
+
+    #[test]
+    fn metadata_only() {
+        let mut private = file("f", "same");
+        private.mode = 0o100600;
+        let plan = reconciled(Some(&[file("f", "same")]), &[private], &[file("f", "same")]);
+        assert_eq!(action(&plan, "f"), Action::LeftToRight);
+        let change = item(&plan, "f").change(Action::LeftToRight).unwrap();
+        assert_eq!(change.change_type(), ChangeType::Clientmetadata);
+    }
+
+    #[test]
+    fn directory_delete_over_updates_below() {
+        let archive = [dir("d"), file("d/a", "a")];
+        // the client deletes the directory, the server adds a file to it
+        let plan = reconciled(
+            Some(&archive),
+            &[],
+            &[dir("d"), file("d/a", "a"), file("d/new", "n")],
+        );
+        assert_eq!(action(&plan, "d"), Action::Conflict);
+        // the client replaces it with a file, the server modifies one in it
+        let plan = reconciled(
+            Some(&archive),
+            &[file("d", "now a file")],
+            &[dir("d"), file("d/a", "changed")],
+        );
+        assert_eq!(action(&plan, "d"), Action::Conflict);
+        // the server replaces it with a link, the client adds to it
+        let link = Node {
+            symlink: true,
+            relative_path: "d".to_string(),
+            link_target: b"elsewhere".to_vec(),
+            ..Default::default()
+        };
+        let plan = reconciled(
+            Some(&archive),
+            &[dir("d"), file("d/a", "a"), file("d/new", "n")],
+            &[link],
+        );
+        assert_eq!(action(&plan, "d"), Action::Conflict);
+        // nothing was updated below it
+        let plan = reconciled(Some(&archive), &[], &archive);
+        assert_eq!(action(&plan, "d"), Action::LeftToRight);
+        assert_eq!(action(&plan, "d/a"), Action::LeftToRight);
+    }
+
+    fn collisions(names: Names, client: &[Node], server: &[Node]) -> Plan {
+        let (client, server) = (entries(client), entries(server));
+        reconcile(
+            &Replica {
+                archive: None,
+                current: &client,
+                names,
+            },
+            &Replica {
+                archive: None,
+                current: &server,
+                names: Names::default(),
+            },
+        )
+    }
+
+    #[test]
+    fn case_collisions() {
+        let case_insensitive = Names {
+            case_insensitive: true,
+            normalization: false,
+        };
+        let server = [dir("Docs"), file("Docs/a", "a"), file("docs", "b")];
+        let plan = collisions(case_insensitive, &[], &server);
+        assert_eq!(action(&plan, "Docs"), Action::Collision);
+        assert_eq!(action(&plan, "docs"), Action::Collision);
+        assert_eq!(item(&plan, "docs").collides_with, "Docs");
+        // everything below a colliding path stays out too
+        assert_eq!(action(&plan, "Docs/a"), Action::Collision);
+        assert!(item(&plan, "Docs/a").change(Action::RightToLeft).is_none());
+        // a file system that keeps case apart takes both
+        let plan = collisions(Names::default(), &[], &server);
+        assert_eq!(action(&plan, "docs"), Action::RightToLeft);
+    }
+
+    #[test]
+    fn normalization_collisions() {
+        let normalization = Names {
+            case_insensitive: false,
+            normalization: true,
+        };
+        let (composed, decomposed) = ("caf\u{e9}", "cafe\u{301}");
+        let server = [
+            file(composed, "a"),
+            file(decomposed, "b"),
+            file("Caf\u{e9}", "c"),
+        ];
+        let plan = collisions(normalization, &[], &server);
+        assert_eq!(action(&plan, composed), Action::Collision);
+        assert_eq!(action(&plan, decomposed), Action::Collision);
+        // case still tells names apart
+        assert_eq!(action(&plan, "Caf\u{e9}"), Action::RightToLeft);
+    }
+}
@@ -0,0 +1,108 @@
+use std::time::{Duration, SystemTime};
+
+use indicatif::{HumanBytes, HumanDuration};
+use serde::Serialize;
+
+use crate::runison::*;
+
+// one updated path of a plan, as printed by `runison plan`
+#[derive(Serialize)]
+struct Entry<'a> {
+    relative_path: &'a str,
+    action: &'static str,
+    // unset for conflicts, which have no change until they are resolved
+    change_type: Option<&'static str>,
+    len: Option<u64>,
+    mod_seconds: Option<u64>,
+    mod_nano: Option<u32>,
+    // for collisions, the other path it would be the same file as
+    collides_with: Option<&'a str>,
+}
+
+fn action_name(action: Action) -> &'static str {
+    match action {
+        Action::Unchanged => "unchanged",
+        Action::LeftToRight => "left-to-right",
+        Action::RightToLeft => "right-to-left",
+        Action::Conflict => "conflict",
+        Action::FalseConflict => "false-conflict",
+        Action::Collision => "collision",
+    }
+}
+
+fn change_name(change_type: ChangeType) -> &'static str {
+    match change_type {
+        ChangeType::Clientadd => "client-add",
+        ChangeType::Clientmodify => "client-modify",
+        ChangeType::Clientdelete => "client-delete",
+        ChangeType::Serveradd => "server-add",
+        ChangeType::Servermodify => "server-modify",
+        ChangeType::Serverdelete => "server-delete",
+        ChangeType::Clientmetadata => "client-metadata",
+        ChangeType::Servermetadata => "server-metadata",
+        ChangeType::Servercopy => "server-copy",
+    }
+}
+
+fn entries(plan: &Plan) -> Vec<Entry> {
+    plan.items
+        .iter()
+        .filter(|item| !item.in_sync())
+        .map(|item| {
+            let change = item.change(item.action());
+            let node = change.as_ref().and_then(|c| c.node.as_ref());
+            Entry {
+                relative_path: &item.relative_path,
+                action: action_name(item.action()),
+                change_type: change.as_ref().map(|c| change_name(c.change_type())),
+                len: node.map(|n| n.len),
+                mod_seconds: node.map(|n| n.mod_seconds),
+                mod_nano: node.map(|n| n.mod_nano),
+                collides_with: Some(item.collides_with.as_str()).filter(|p| !p.is_empty()),
+            }
+        })
+        .collect()
+}
+
+// print the updated paths of a plan as JSON
+pub fn print_json(plan: &Plan) -> serde_json::Result<()> {
+    println!("{}", serde_json::to_string_pretty(&entries(plan))?);
+    Ok(())
+}
+
+// print the updated paths of a plan as a table
+pub fn print_table(plan: &Plan) {
+    let entries = entries(plan);
+    if entries.is_empty() {
+        println!("Nothing to synchronize");
+        return;
+    }
+    println!(
+        "{:<14} {:<16} {:>10} {:<16} {}",
+        "ACTION", "CHANGE", "SIZE", "MODIFIED", "PATH"
+    );
+    for entry in entries {
+        let modified = entry.mod_seconds.map(|seconds| {
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+            let age = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default();
+            format!("{} ago", HumanDuration(age))
+        });
+        let path = match entry.collides_with {
+            Some(other) => format!("{} (collides with {})", entry.relative_path, other),
+            None => entry.relative_path.to_string(),
+        };
+        println!(
+            "{:<14} {:<16} {:>10} {:<16} {}",
+            entry.action,
+            entry.change_type.unwrap_or("-"),
+            entry
+                .len
+                .map(|len| HumanBytes(len).to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            modified.unwrap_or_else(|| "-".to_string()),
+            path
+        );
+    }
+}
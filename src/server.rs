@@ -1,13 +1,20 @@
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use prost_types::compiler::code_generator_response::File;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::transport::Server;
-use tonic::{Request, Response, Status};
+use tonic::{Request, Response, Status, Streaming};
 
 use runison::synchronizer_server::{Synchronizer, SynchronizerServer};
 
-use runison::{ChangeSetResponse, Entries};
+use runison::{
+    ApplyResponse, Change, ChangeSetRequest, ChangeSetResponse, ChangeType, Chunk, CommitRequest,
+    CommitResponse, Delta, FileRequest, Node, PlanItem, PushResponse, Signature,
+};
 use std::path::PathBuf;
+use std::{fs, io};
 use structopt::StructOpt;
 
 pub mod runison {
@@ -15,9 +22,18 @@ pub mod runison {
 }
 
 mod config;
+mod delta;
+mod filter;
 mod node;
+mod reconcile;
 mod synchronizer;
+mod tls;
+mod transfer;
+use config::{Access, Acl, Client, Config, Transfer};
+use delta::{check_block_size, diff, signature, Patcher};
+use filter::Filter;
 use synchronizer::Synchronizer as FileSynchronizer;
+use transfer::{read_ahead, read_chunks};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "runison-server", about = "A modern file synchronization tool.")]
@@ -30,26 +46,541 @@ struct Opt {
     /// Configuration file
     #[structopt(short = "c", long = "config", parse(from_os_str))]
     config: PathBuf,
+
+    /// Address to listen on, overriding [server] listen
+    #[structopt(short = "l", long = "listen")]
+    listen: Option<String>,
+}
+pub struct SynchronizerService {
+    // the [root] replica under None, named roots under their names
+    roots: BTreeMap<Option<String>, Root>,
+    clients: BTreeMap<String, Client>,
+}
+// a replica the server serves, and the synchronizations in progress on it
+struct Root {
+    config: Config,
+    // by session. each has a synchronizer of its own, holding the state
+    // of that synchronization from its plan to its commit
+    sessions: Mutex<HashMap<String, Session>>,
+}
+struct Session {
+    // the client replica, as the key of its archive
+    peer: String,
+    // name of the client that started it in [clients]
+    client: String,
+    synchronizer: Arc<Mutex<FileSynchronizer>>,
+}
+impl Root {
+    fn new(config: Config) -> Root {
+        Root {
+            config,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+    // register a synchronization that was just planned, returning its
+    // session. an earlier one of the same client replica would write the
+    // same archive, so it is superseded: its client is gone, or fails on
+    // its next request
+    fn start(&self, client: &str, synchronizer: FileSynchronizer) -> Result<String, Status> {
+        let id = format!("{:032x}", rand::random::<u128>());
+        let peer = synchronizer.peer.clone().unwrap_or_default();
+        let mut sessions = self.sessions.lock().map_err(poisoned)?;
+        sessions.retain(|_, session| session.peer != peer);
+        sessions.insert(
+            id.clone(),
+            Session {
+                peer,
+                client: client.to_string(),
+                synchronizer: Arc::new(Mutex::new(synchronizer)),
+            },
+        );
+        Ok(id)
+    }
+}
+impl SynchronizerService {
+    // the replica a request is for, what its client may do there and the
+    // synchronization it belongs to. requests name a root in the
+    // runison-root header, or use [root]
+    fn scope<T>(&self, request: &Request<T>) -> Result<Scope<'_>, Status> {
+        let name = request
+            .metadata()
+            .get("runison-root")
+            .map(|value| {
+                value
+                    .to_str()
+                    .map(String::from)
+                    .map_err(|_| Status::invalid_argument("invalid root name"))
+            })
+            .transpose()?;
+        let root = self.roots.get(&name).ok_or_else(|| {
+            Status::not_found(format!("no root {}", name.as_deref().unwrap_or("[root]")))
+        })?;
+        // without [clients] the server is open to everyone
+        let (client, acl) = if self.clients.is_empty() {
+            (String::new(), None)
+        } else {
+            let token = token(request);
+            let (client, config) = self
+                .clients
+                .iter()
+                .find(|(_, client)| token.map_or(false, |t| same_token(t, &client.token)))
+                .ok_or_else(|| Status::unauthenticated("unknown client"))?;
+            let acl = config.acl(name.as_deref()).ok_or_else(|| {
+                Status::permission_denied(format!(
+                    "no access to root {}",
+                    name.as_deref().unwrap_or("[root]")
+                ))
+            })?;
+            (client.clone(), Some(acl))
+        };
+        let session = match request.metadata().get("runison-session") {
+            Some(value) => {
+                let id = value
+                    .to_str()
+                    .map_err(|_| Status::invalid_argument("invalid session"))?;
+                let sessions = root.sessions.lock().map_err(poisoned)?;
+                match sessions.get(id) {
+                    Some(session) if session.client == client => {
+                        Some((id.to_string(), Arc::clone(&session.synchronizer)))
+                    }
+                    _ => {
+                        return Err(Status::failed_precondition(
+                            "the synchronization is over or was superseded by another one",
+                        ))
+                    }
+                }
+            }
+            None => None,
+        };
+        Ok(Scope {
+            root,
+            client,
+            acl,
+            session,
+        })
+    }
+}
+// the replica a request is for, and the access its client has there
+struct Scope<'a> {
+    root: &'a Root,
+    // name of the client in [clients], empty if the server is open to
+    // every client
+    client: String,
+    // None if the server is open to every client
+    acl: Option<Acl>,
+    // the synchronization the request belongs to, and its synchronizer
+    session: Option<(String, Arc<Mutex<FileSynchronizer>>)>,
+}
+impl<'a> Scope<'a> {
+    // the synchronizer of the synchronization the request belongs to
+    fn synchronizer(&self) -> Result<&Arc<Mutex<FileSynchronizer>>, Status> {
+        match &self.session {
+            Some((_, synchronizer)) => Ok(synchronizer),
+            None => Err(Status::failed_precondition(
+                "no synchronization in progress, get a change set first",
+            )),
+        }
+    }
+    // the same, locked for the duration of a short step
+    fn lock(&self) -> Result<MutexGuard<'_, FileSynchronizer>, Status> {
+        self.synchronizer()?.lock().map_err(poisoned)
+    }
+    // forget the synchronization the request belongs to once it is over
+    fn end(&self) -> Result<(), Status> {
+        if let Some((id, _)) = &self.session {
+            self.root.sessions.lock().map_err(poisoned)?.remove(id);
+        }
+        Ok(())
+    }
+    // refuse a request for a path its client may not access, either as
+    // named or where the links it goes through lead
+    fn authorize(&self, relative_path: &str, access: Access) -> Result<(), Status> {
+        let acl = match &self.acl {
+            Some(acl) => acl,
+            None => return Ok(()),
+        };
+        let resolved = {
+            let synchronizer = self.lock()?;
+            synchronizer.resolved(relative_path)
+        }
+        .ok_or_else(|| Status::invalid_argument(format!("invalid path {}", relative_path)))?;
+        for path in &[relative_path, &resolved] {
+            if !acl.allows(path, access) {
+                return Err(Status::permission_denied(format!(
+                    "no {} access to {}",
+                    match access {
+                        Access::Read => "read",
+                        Access::Write => "write",
+                    },
+                    path
+                )));
+            }
+        }
+        Ok(())
+    }
+    // resolve a relative path from a request to the file to read inside
+    // the root, through a last link if it is followed
+    fn source_path(&self, relative_path: &str) -> Result<PathBuf, Status> {
+        let synchronizer = self.lock()?;
+        synchronizer
+            .source_path(relative_path)
+            .ok_or_else(|| Status::invalid_argument(format!("invalid path {}", relative_path)))
+    }
+    // how this replica sends file contents
+    fn transfer(&self) -> Result<Transfer, Status> {
+        let synchronizer = self.lock()?;
+        Ok(synchronizer.config.transfer.clone())
+    }
+    // give a file the client just sent the attributes of its node,
+    // and record its new state
+    fn received(&self, node: &Node) -> Result<(), Status> {
+        let mut synchronizer = self.lock()?;
+        synchronizer.apply_attributes(node).map_err(io_status)?;
+        synchronizer.record(&node.relative_path);
+        Ok(())
+    }
 }
-#[derive(Copy, Clone)]
-struct SyncWrapper<'a> {
-    sync_ref: &'a FileSynchronizer,
+// the token a request carries in its authorization header
+fn token<T>(request: &Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
 }
-pub struct SynchronizerService<'a> {
-    synchronizer: SyncWrapper<'a>,
+// run a step that keeps the file system busy for a while on a thread
+// of its own, away from the threads serving requests
+async fn blocking<T, F>(step: F) -> Result<T, Status>
+where
+    F: FnOnce() -> Result<T, Status> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(step)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+}
+// returns true if a request carries a client's token. the tokens are
+// compared through their hashes, which compare in constant time, so how
+// long it takes doesn't tell how much of a guess was right
+fn same_token(token: &str, expected: &str) -> bool {
+    blake3::hash(token.as_bytes()) == blake3::hash(expected.as_bytes())
+}
+fn poisoned<T>(_: T) -> Status {
+    Status::internal("synchronizer lock poisoned")
+}
+// the key a client replica's archive is kept under, from the name of
+// the client and the replica it says it is, safe in a file name
+fn peer(client: &str, replica: &str) -> String {
+    let key = format!("{}\0{}", client, replica);
+    blake3::hash(key.as_bytes()).to_hex()[..16].to_string()
+}
+fn io_status(error: io::Error) -> Status {
+    match error.kind() {
+        io::ErrorKind::NotFound => Status::not_found(error.to_string()),
+        io::ErrorKind::PermissionDenied => Status::permission_denied(error.to_string()),
+        io::ErrorKind::InvalidInput => Status::invalid_argument(error.to_string()),
+        _ => Status::internal(error.to_string()),
+    }
 }
 #[tonic::async_trait]
 impl Synchronizer for SynchronizerService {
     async fn get_change_set(
         &self,
-        request: Request<Entries>,
+        request: Request<ChangeSetRequest>,
     ) -> Result<Response<ChangeSetResponse>, Status> {
-        println!("GetChangeSet = {:?}", request);
-        &self
-            .synchronizer
-            .sync_ref
-            .remote_changes(request.into_inner());
-        Ok(Response::new(ChangeSetResponse::default()))
+        println!("GetChangeSet from {:?}", request.remote_addr());
+        let scope = self.scope(&request)?;
+        let request = request.into_inner();
+        let dry_run = request.dry_run;
+        let config = scope.root.config.clone();
+        let peer = peer(&scope.client, &request.replica);
+        let acl = scope.acl.clone();
+        // indexing a large replica takes a while, and would hold up every
+        // other request on the runtime's threads
+        let (synchronizer, plan) = blocking(move || {
+            // every synchronization gets a synchronizer of its own, which
+            // reads and writes the archive kept for the client replica
+            let mut synchronizer =
+                FileSynchronizer::new(config).map_err(|e| Status::internal(e.to_string()))?;
+            synchronizer.peer = Some(peer);
+            for path in &request.paths {
+                if synchronizer.local_path(path).is_none() {
+                    return Err(Status::invalid_argument(format!("invalid path {}", path)));
+                }
+            }
+            // index on every request so the plan reflects the replica as it is now,
+            // limited to the subtrees both replicas synchronize
+            synchronizer.select(&request.paths);
+            synchronizer.dry_run = request.dry_run;
+            synchronizer.index();
+            let mut plan = synchronizer.plan(&request);
+            if let Some(acl) = &acl {
+                // paths the client may not read, as named or where they
+                // resolve to, are left out of the plan, and stay in the
+                // archive as they were
+                let (visible, hidden): (Vec<PlanItem>, Vec<PlanItem>) =
+                    plan.items.into_iter().partition(|item| {
+                        acl.allows(&item.relative_path, Access::Read)
+                            && synchronizer
+                                .resolved(&item.relative_path)
+                                .map_or(false, |path| acl.allows(&path, Access::Read))
+                    });
+                for item in &hidden {
+                    synchronizer.keep_archived(&item.relative_path);
+                }
+                plan.items = visible;
+            }
+            Ok((synchronizer, plan))
+        })
+        .await?;
+        let paths = synchronizer.paths.clone();
+        // a dry run is over once it is planned
+        let session = if dry_run {
+            String::new()
+        } else {
+            scope.root.start(&scope.client, synchronizer)?
+        };
+        Ok(Response::new(ChangeSetResponse {
+            change: plan.changes(),
+            plan: Some(plan),
+            paths,
+            session,
+        }))
+    }
+
+    async fn get_signature(
+        &self,
+        request: Request<FileRequest>,
+    ) -> Result<Response<Signature>, Status> {
+        let scope = self.scope(&request)?;
+        let request = request.into_inner();
+        println!("GetSignature = {}", request.relative_path);
+        scope.authorize(&request.relative_path, Access::Read)?;
+        let path = scope.source_path(&request.relative_path)?;
+        let signature = signature(&path, &request.relative_path).map_err(io_status)?;
+        Ok(Response::new(signature))
+    }
+
+    async fn push_delta(
+        &self,
+        request: Request<Streaming<Delta>>,
+    ) -> Result<Response<PushResponse>, Status> {
+        let scope = self.scope(&request)?;
+        let mut stream = request.into_inner();
+        // the first delta names the file and carries the client node
+        let first = match stream.message().await? {
+            Some(delta) => delta,
+            None => return Err(Status::invalid_argument("empty push")),
+        };
+        println!("PushDelta = {}", first.relative_path);
+        scope.authorize(&first.relative_path, Access::Write)?;
+        let node = match &first.node {
+            Some(node) => node.clone(),
+            None => return Err(Status::invalid_argument("push without node")),
+        };
+        // attributes are applied to the path the node names, which
+        // has to be the one authorized above
+        if node.relative_path != first.relative_path {
+            return Err(Status::invalid_argument("push for another path's node"));
+        }
+        let (basis, staged) = {
+            let synchronizer = scope.lock()?;
+            let staged = synchronizer
+                .stage(&first.relative_path)
+                .map_err(io_status)?;
+            (synchronizer.source_path(&first.relative_path), staged)
+        };
+        let basis = basis.ok_or_else(|| Status::invalid_argument("invalid path"))?;
+        let mut patcher = Patcher::new(&basis, staged, first.block_size).map_err(io_status)?;
+        if let Some(op) = &first.op {
+            patcher.apply(op).map_err(io_status)?;
+        }
+        while let Some(delta) = stream.message().await? {
+            if let Some(op) = &delta.op {
+                patcher.apply(op).map_err(io_status)?;
+            }
+        }
+        let len = patcher.commit(&node).map_err(io_status)?;
+        scope.received(&node)?;
+        Ok(Response::new(PushResponse { len }))
+    }
+
+    type PullDeltaStream = ReceiverStream<Result<Delta, Status>>;
+
+    async fn pull_delta(
+        &self,
+        request: Request<Signature>,
+    ) -> Result<Response<Self::PullDeltaStream>, Status> {
+        let scope = self.scope(&request)?;
+        let signature = request.into_inner();
+        println!("PullDelta = {}", signature.relative_path);
+        scope.authorize(&signature.relative_path, Access::Read)?;
+        let path = scope.source_path(&signature.relative_path)?;
+        check_block_size(signature.block_size).map_err(io_status)?;
+        let (tx, rx) = mpsc::channel(4);
+        tokio::task::spawn_blocking(move || {
+            let mut block_size = signature.block_size;
+            let result = diff(&path, &signature, |op| {
+                tx.blocking_send(Ok(Delta {
+                    relative_path: signature.relative_path.clone(),
+                    node: None,
+                    // only the first delta carries it
+                    block_size: std::mem::take(&mut block_size),
+                    op: Some(op),
+                }))
+                .is_ok()
+            });
+            if let Err(e) = result {
+                let _ = tx.blocking_send(Err(io_status(e)));
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type PullFileStream = ReceiverStream<Result<Chunk, Status>>;
+
+    async fn pull_file(
+        &self,
+        request: Request<FileRequest>,
+    ) -> Result<Response<Self::PullFileStream>, Status> {
+        let scope = self.scope(&request)?;
+        let request = request.into_inner();
+        println!("PullFile = {}", request.relative_path);
+        scope.authorize(&request.relative_path, Access::Read)?;
+        let path = scope.source_path(&request.relative_path)?;
+        let transfer = scope.transfer()?;
+        let len = fs::metadata(&path).map_err(io_status)?.len();
+        let (tx, rx) = mpsc::channel(read_ahead(len, &transfer));
+        tokio::task::spawn_blocking(move || {
+            let result = read_chunks(&path, &request.relative_path, &transfer, |chunk| {
+                tx.blocking_send(Ok(chunk)).is_ok()
+            });
+            if let Err(e) = result {
+                let _ = tx.blocking_send(Err(io_status(e)));
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn push_file(
+        &self,
+        request: Request<Streaming<Chunk>>,
+    ) -> Result<Response<PushResponse>, Status> {
+        let scope = self.scope(&request)?;
+        let mut stream = request.into_inner();
+        // the first chunk names the file and carries the client node
+        let first = match stream.message().await? {
+            Some(chunk) => chunk,
+            None => return Err(Status::invalid_argument("empty push")),
+        };
+        println!("PushFile = {}", first.relative_path);
+        scope.authorize(&first.relative_path, Access::Write)?;
+        let node = match &first.node {
+            Some(node) => node.clone(),
+            None => return Err(Status::invalid_argument("push without node")),
+        };
+        // attributes are applied to the path the node names, which
+        // has to be the one authorized above
+        if node.relative_path != first.relative_path {
+            return Err(Status::invalid_argument("push for another path's node"));
+        }
+        let mut staged = {
+            let synchronizer = scope.lock()?;
+            synchronizer
+                .stage(&first.relative_path)
+                .map_err(io_status)?
+        };
+        staged.write(&first).map_err(io_status)?;
+        // an error or disconnect drops the staged file before it is committed
+        while let Some(chunk) = stream.message().await? {
+            staged.write(&chunk).map_err(io_status)?;
+        }
+        let len = staged.commit(&node).map_err(io_status)?;
+        scope.received(&node)?;
+        Ok(Response::new(PushResponse { len }))
+    }
+
+    async fn apply_change(
+        &self,
+        request: Request<Change>,
+    ) -> Result<Response<ApplyResponse>, Status> {
+        let scope = self.scope(&request)?;
+        let change = request.into_inner();
+        let change_type = change.change_type();
+        let node = match change.node {
+            Some(node) => node,
+            None => return Err(Status::invalid_argument("change without node")),
+        };
+        let target = change.target;
+        println!("ApplyChange = {:?} {}", change_type, node.relative_path);
+        scope.authorize(&node.relative_path, Access::Write)?;
+        if change_type == ChangeType::Servercopy {
+            scope.authorize(&target, Access::Write)?;
+        }
+        // a hard link gives access to the contents of the file it links to
+        if !node.hard_link.is_empty() {
+            scope.authorize(&node.hard_link, Access::Read)?;
+        }
+        // deleting a directory tree may take a while
+        let synchronizer = Arc::clone(scope.synchronizer()?);
+        blocking(move || {
+            let mut synchronizer = synchronizer.lock().map_err(poisoned)?;
+            let node = &node;
+            match change_type {
+                ChangeType::Clientadd | ChangeType::Clientmodify if node.dir => synchronizer
+                    .make_dir(&node.relative_path)
+                    .and_then(|_| synchronizer.apply_attributes(node)),
+                ChangeType::Clientadd | ChangeType::Clientmodify if node.symlink => {
+                    synchronizer.make_link(node)
+                }
+                ChangeType::Clientadd | ChangeType::Clientmodify if !node.hard_link.is_empty() => {
+                    synchronizer
+                        .hard_link(node)
+                        .and_then(|_| synchronizer.apply_attributes(node))
+                }
+                ChangeType::Clientdelete => synchronizer.remove(node),
+                ChangeType::Clientmetadata => synchronizer.apply_attributes(node),
+                ChangeType::Servercopy => synchronizer
+                    .copy(node, &target)
+                    .map(|_| synchronizer.record(&target)),
+                change_type => {
+                    return Err(Status::invalid_argument(format!(
+                        "can't apply {:?} without contents",
+                        change_type
+                    )))
+                }
+            }
+            .map_err(io_status)?;
+            synchronizer.record(&node.relative_path);
+            Ok(())
+        })
+        .await?;
+        Ok(Response::new(ApplyResponse {}))
+    }
+
+    async fn commit(
+        &self,
+        request: Request<CommitRequest>,
+    ) -> Result<Response<CommitResponse>, Status> {
+        let scope = self.scope(&request)?;
+        let request = request.into_inner();
+        println!("Commit, {} unsynchronized", request.unsynchronized.len());
+        // a client that may only read commits as well: the archive it
+        // writes is the one kept for its own replica, which no other
+        // client's synchronization reads
+        let synchronizer = Arc::clone(scope.synchronizer()?);
+        blocking(move || {
+            let mut synchronizer = synchronizer.lock().map_err(poisoned)?;
+            for path in &request.unsynchronized {
+                synchronizer.keep_archived(path);
+            }
+            synchronizer
+                .save_archive()
+                .map_err(|e| Status::internal(e.to_string()))
+        })
+        .await?;
+        scope.end()?;
+        Ok(Response::new(CommitResponse {}))
     }
 }
 
@@ -61,20 +592,53 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     match result {
         Ok(config) => {
-            let addr = "[::1]:10000".parse().unwrap();
+            let listen = opt.listen.unwrap_or_else(|| config.server.listen.clone());
+            let addr = listen.parse()?;
+            let tls = tls::server_config(&config.tls)?;
 
             println!("Synchronizer listening on: {}", addr);
 
+            // only requests carrying the token of a configured client get through
+            let tokens: Vec<String> = config.clients.values().map(|c| c.token.clone()).collect();
+            let mut roots = BTreeMap::new();
+            for name in config.roots.keys() {
+                let root = config.root_config(name).unwrap();
+                println!("Serving root {} from {}", name, root.root.path);
+                // fail early on invalid [ignore] patterns
+                Filter::new(&root)?;
+                roots.insert(Some(name.clone()), Root::new(root));
+            }
+            if !config.root.path.is_empty() {
+                println!("Serving [root] from {}", config.root.path);
+                Filter::new(&config)?;
+                roots.insert(None, Root::new(config.clone()));
+            }
             let synchronizer = SynchronizerService {
-                synchronizer: SyncWrapper { sync_ref: &synch },
+                clients: config.clients.clone(),
+                roots,
             };
 
-            let svc = SynchronizerServer::new(synchronizer);
+            let svc = if tokens.is_empty() {
+                SynchronizerServer::new(synchronizer)
+            } else {
+                SynchronizerServer::with_interceptor(synchronizer, move |request: Request<()>| {
+                    match token(&request) {
+                        Some(token) if tokens.iter().any(|t| same_token(token, t)) => Ok(request),
+                        _ => Err(Status::unauthenticated("invalid token")),
+                    }
+                })
+            };
 
-            Server::builder().add_service(svc).serve(addr).await;
+            let mut builder = Server::builder();
+            if let Some(tls) = tls {
+                builder = builder.tls_config(tls)?;
+            }
+            builder.add_service(svc).serve(addr).await?;
         }
 
-        Err(_) => {}
+        Err(error) => {
+            println!("Error: {:?}", error);
+        }
     }
     Ok(())
 }
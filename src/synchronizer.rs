@@ -1,18 +1,23 @@
 use std::{
     collections::HashMap,
-    fs,
-    io::{self, BufReader},
-    path::PathBuf,
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Component, PathBuf},
     time::Instant,
 };
 
-use crate::config::{Config, Path};
-use glob::Pattern;
+use crate::config::{intersect, path_within, Config, Path};
+use crate::filter::Filter;
+use crate::node::{escape, fingerprint, follows, unescape};
+use crate::reconcile::{reconcile, Replica};
+use crate::transfer::{temp_next_to, Staged, CHUNK_SIZE};
 use indicatif::{HumanDuration, ProgressBar, ProgressStyle};
 
 use crate::runison::*;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use walkdir::{DirEntry, WalkDir};
 #[derive(Debug, Serialize, Deserialize, PartialEq, Copy, Clone)]
 // Operational status of the process
 pub enum Status {
@@ -22,46 +27,143 @@ pub enum Status {
     Stopping,
 }
 
+// archive of the replica state at the last synchronization
+const CURRENT_ARCHIVE: &str = ".runison-current";
+// archive of the replica state at the synchronization before that
+const PREVIOUS_ARCHIVE: &str = ".runison-previous";
+
 pub struct Synchronizer {
     pub entries: Entries,
     pub config: Config,
-    pub first_run: bool,
+    // replica state loaded from the archive before indexing,
+    // None if no archive has been written yet
+    pub archive: Option<Entries>,
+    // only plan, never writing the archive
+    pub dry_run: bool,
+    // the replica this one synchronizes with, for a server, which keeps
+    // a separate archive for every client replica. None on the client
+    pub peer: Option<String>,
+    // subtrees of the root to synchronize, "." for all of it
+    pub paths: Vec<String>,
+    // archived state of paths outside those subtrees, which is
+    // carried over into new archives as it is
+    unselected: HashMap<String, Node>,
+    // paths left out of synchronization
+    pub filter: Filter,
 }
 impl Synchronizer {
-    pub fn new(config: Config) -> Option<Synchronizer> {
-        Some(Synchronizer {
+    pub fn new(config: Config) -> Result<Synchronizer, Box<dyn std::error::Error>> {
+        let mut synchronizer = Synchronizer {
+            filter: Filter::new(&config)?,
             entries: Entries {
                 nodes: HashMap::new(),
             },
             config,
-            first_run: false,
-        })
+            archive: None,
+            dry_run: false,
+            peer: None,
+            paths: Vec::new(),
+            unselected: HashMap::new(),
+        };
+        synchronizer.paths = synchronizer.configured();
+        Ok(synchronizer)
+    }
+    // the subtrees [path] directories selects, all of the root if none
+    fn configured(&self) -> Vec<String> {
+        let directories = &self.config.path.directories;
+        if directories.is_empty() {
+            return vec![String::from(".")];
+        }
+        directories.iter().map(|d| subtree(d)).collect()
+    }
+    // narrow the configured subtrees down to the given ones. the state of
+    // paths outside them is dropped, and their archived state kept aside
+    pub fn select(&mut self, paths: &[String]) {
+        let paths: Vec<String> = paths
+            .iter()
+            .filter(|path| self.local_path(path).is_some())
+            .map(|path| subtree(path))
+            .collect();
+        self.paths = intersect(&self.configured(), &paths);
+        let selected = self.paths.clone();
+        let within = |path: &String| selected.iter().any(|s| path_within(path, s));
+        self.entries.nodes.retain(|path, _| within(path));
+        if let Some(archive) = &mut self.archive {
+            let (kept, unselected): (HashMap<_, _>, HashMap<_, _>) =
+                archive.nodes.drain().partition(|(path, _)| within(path));
+            archive.nodes = kept;
+            self.unselected.extend(unselected);
+        }
+    }
+    // returns true if a path lies in one of the selected subtrees
+    pub fn selected(&self, relative_path: &str) -> bool {
+        self.paths.iter().any(|s| path_within(relative_path, s))
+    }
+    fn archive_path(&self, name: &str) -> PathBuf {
+        let mut archive = PathBuf::from(&self.config.root.path);
+        match &self.peer {
+            Some(peer) => archive.push(format!("{}-{}", name, peer)),
+            None => archive.push(name),
+        }
+        archive
     }
-    fn move_index(&mut self) -> io::Result<()> {
-        // skip the move if the file won't be there
-        if self.first_run {
+    // read a bincode encoded archive from the root of the replica,
+    // returning None if it doesn't exist or can't be decoded
+    pub fn load_archive(&self, name: &str) -> Option<Entries> {
+        let file = fs::File::open(self.archive_path(name)).ok()?;
+        match bincode::deserialize_from(BufReader::new(file)) {
+            Ok(entries) => Some(entries),
+            Err(e) => {
+                eprintln!("Error reading archive {}: {:?}", name, e);
+                None
+            }
+        }
+    }
+    // record the state of the replica once a synchronization is over,
+    // keeping the archive it replaces as the previous one. the new archive
+    // is written to a temporary file first and renamed into place, so an
+    // interrupted run leaves the last synchronized state as it was
+    pub fn save_archive(&self) -> bincode::Result<()> {
+        if self.dry_run {
             return Ok(());
         }
-        let config = self.config.clone();
-        let rp = String::from(config.root.path.clone());
-
-        let mut archive = PathBuf::from(&rp);
-        archive.push(".runison-current");
-
-        let mut newarchive = PathBuf::from(&rp);
-        newarchive.push(".runison-previous");
-        println!("Moving index to {:?}", newarchive.display());
-        fs::rename(archive, newarchive) // Rename a.txt to b.txt
+        let mut entries = self.entries.clone();
+        for (path, node) in &self.unselected {
+            entries.nodes.insert(path.clone(), node.clone());
+        }
+        let current = self.archive_path(CURRENT_ARCHIVE);
+        let temp = temp_next_to(&current);
+        let written = fs::File::create(&temp)
+            .map_err(bincode::Error::from)
+            .and_then(|f| {
+                let mut writer = BufWriter::new(f);
+                bincode::serialize_into(&mut writer, &entries)?;
+                let f = writer.into_inner().map_err(|e| e.into_error())?;
+                Ok(f.sync_all()?)
+            });
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        let previous = self.archive_path(PREVIOUS_ARCHIVE);
+        let _ = fs::remove_file(&previous);
+        match fs::hard_link(&current, &previous) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => eprintln!("Error: {:?}", e),
+            _ => {}
+        }
+        fs::rename(&temp, &current)?;
+        Ok(())
     }
     pub fn index(&mut self) {
         let started = Instant::now();
-        match self.move_index() {
-            Ok(_) => {}
-            Err(_) => {
-                self.first_run = true;
-            }
-        }
-        println!("Indexing files...");
+        self.archive = self.load_archive(CURRENT_ARCHIVE);
+        self.entries.nodes.clear();
+        self.unselected.clear();
+        self.filter.reload();
+        // only the selected subtrees are indexed and reconciled
+        let paths = self.paths.clone();
+        self.select(&paths);
+        eprintln!("Indexing files...");
         let pb = ProgressBar::new_spinner();
         pb.enable_steady_tick(200);
         pb.set_style(
@@ -71,226 +173,621 @@ impl Synchronizer {
         );
         let config = self.config.clone();
         let rp = String::from(config.root.path.clone());
+        let root_path = PathBuf::from(&rp);
         //    self.entries
         //       .insert(".".to_string(), Node::from_path(&rp, &config).unwrap());
-        for entry in WalkDir::new(&rp)
-            .into_iter()
-            .filter_entry(|e| !ignored(e, &config.clone()))
-        {
-            let root_path = PathBuf::from(&rp);
-            match entry {
-                Ok(ent) => {
-                    let config = self.config.clone();
-                    let fp: String;
-                    // if the path of the entry is the same as
-                    // the root path, the entry key will be "" unless
-                    // we specify it manually
-                    if ent.path().to_str().unwrap().to_string().len()
-                        == root_path.to_str().unwrap().to_string().len()
-                    {
-                        fp = String::from(".");
-                    } else {
-                        fp = ent
-                            .path()
-                            .strip_prefix(&rp)
-                            .unwrap()
-                            .to_str()
-                            .unwrap()
-                            .to_string();
-                    }
-                    pb.set_message(&fp.clone());
-                    self.entries.nodes.insert(
-                        fp.clone(),
-                        Node::from_path(PathBuf::from(&rp), PathBuf::from(&fp), &config).unwrap(),
-                    );
-                    pb.tick();
+        // walk each selected subtree, which may not exist yet
+        let starts: Vec<PathBuf> = self
+            .paths
+            .iter()
+            .filter_map(|path| self.local_path(path))
+            .filter(|start| start.exists())
+            .collect();
+        // directories are read in parallel, and so are the files they
+        // hold stat'ed and hashed
+        let follow = config.links.follow;
+        let paths: Vec<PathBuf> = starts
+            .into_par_iter()
+            .flat_map_iter(|start| {
+                // a selected subtree that is a link is walked only if followed
+                let is_dir = if start == root_path {
+                    start.is_dir()
+                } else {
+                    self.through_link(start.clone())
+                        .map(|path| path.is_dir())
+                        .unwrap_or(false)
+                };
+                if self.filter.ignored(&start, is_dir) {
+                    return Vec::new();
                 }
-                Err(_) => {}
-            }
+                walk(&self.filter, follow, &root_path, start, is_dir, &[])
+            })
+            .collect();
+        let nodes: Vec<(String, Node)> = paths
+            .into_par_iter()
+            .filter_map(|path| {
+                // if the path of the entry is the same as
+                // the root path, the entry key will be "" unless
+                // we specify it manually
+                let relative_path = if path.as_os_str().len() == root_path.as_os_str().len() {
+                    PathBuf::from(".")
+                } else {
+                    path.strip_prefix(&rp).unwrap().to_path_buf()
+                };
+                let fp = escape(relative_path.as_os_str());
+                pb.set_message(&fp);
+                // None if it is gone since the walk saw it
+                let mut node = Node::from_path(root_path.clone(), relative_path, &config)?;
+                if node.escapes_root() && config.links.reject_escaping {
+                    eprintln!("Skipping {}, it links outside of the root", fp);
+                    return None;
+                }
+                if node.file {
+                    node.fingerprint = self.fingerprint(&fp, &node);
+                }
+                pb.tick();
+                Some((fp, node))
+            })
+            .collect();
+        self.entries.nodes.extend(nodes);
+        if config.links.hard {
+            self.find_hard_links();
         }
         pb.finish_and_clear();
-        println!("Done indexing in {}", HumanDuration(started.elapsed()));
-        /*
-        let mut archive = PathBuf::from(&rp);
-        archive.push(".runison-current");
-        {
-            let f = std::fs::File::create(archive).unwrap();
-            bincode::serialize_into(f, &self.entries).unwrap();
+        eprintln!("Done indexing in {}", HumanDuration(started.elapsed()));
+    }
+    // mark every file that is a hard link of another indexed file with
+    // the first path of their group, the only one whose contents are sent
+    fn find_hard_links(&mut self) {
+        let mut leaders: HashMap<(u64, u64), String> = HashMap::new();
+        let mut files: Vec<(&String, &Node)> =
+            self.entries.nodes.iter().filter(|(_, n)| n.file).collect();
+        files.sort_by(|a, b| a.0.cmp(b.0));
+        for (path, node) in files {
+            leaders
+                .entry((node.device, node.inode))
+                .or_insert_with(|| path.clone());
+        }
+        for (path, node) in self.entries.nodes.iter_mut() {
+            node.hard_link = match leaders.get(&(node.device, node.inode)) {
+                Some(leader) if node.file && leader != path => leader.clone(),
+                _ => String::new(),
+            };
         }
-        */
     }
-    pub fn remote_changes(self, remote_tree: Entries) -> Option<Vec<Change>> {
-        println!("Detecting file changeset...");
-
-        let started = Instant::now();
-        let mut changes = Vec::new();
-        for (path, node) in &self.entries.nodes {
-            if let Some(remote) = remote_tree.nodes.get(path) {
-                // exists in both, check for change
-                if !node.dir && node.mod_seconds != remote.mod_seconds {
-                    // changed file
-                    if node.mod_seconds > remote.mod_seconds {
-                        changes.push(Change {
-                            change_type: ChangeType::Clientmodify as i32,
-                            node: Some(node.clone()),
-                        })
-                    }
-                }
-            } else {
-                // doesn't exist locally, is new file
-                changes.push(Change {
-                    change_type: ChangeType::Clientadd as i32,
-                    node: Some(node.clone()),
-                })
+    // hash the contents of a file, reusing the archived fingerprint
+    // when the file still looks exactly like it did when it was archived
+    fn fingerprint(&self, path: &str, node: &Node) -> Vec<u8> {
+        if let Some(archived) = self.archive.as_ref().and_then(|a| a.nodes.get(path)) {
+            if archived.file
+                && archived.inode == node.inode
+                && !archived.fingerprint.is_empty()
+                && !node.differs_from(archived)
+            {
+                return archived.fingerprint.clone();
             }
         }
-        for (path, remote) in remote_tree.nodes {
-            match self.entries.nodes.get(&path) {
-                Some(_) => {}
-                None => {
-                    // remote file doesn't exist locally
-                    changes.push(Change {
-                        change_type: ChangeType::Serveradd as i32,
-                        node: Some(remote.clone()),
+        match fingerprint(OsStr::from_bytes(&node.path)) {
+            Ok(fingerprint) => fingerprint,
+            Err(e) => {
+                eprintln!("Error hashing {}: {:?}", path, e);
+                Vec::new()
+            }
+        }
+    }
+    // update the state recorded for a path after a change was applied
+    // to it, so the next archive reflects the synchronized replica
+    pub fn record(&mut self, relative_path: &str) {
+        let path = match self.local_path(relative_path) {
+            Some(path) => path,
+            None => return,
+        };
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                // deleted, together with anything below it
+                let prefix = format!("{}/", relative_path);
+                self.entries
+                    .nodes
+                    .retain(|p, _| p != relative_path && !p.starts_with(&prefix));
+                return;
+            }
+        };
+        let config = self.config.clone();
+        let root_path = PathBuf::from(&config.root.path);
+        if let Some(mut node) =
+            Node::from_path(root_path, PathBuf::from(unescape(relative_path)), &config)
+        {
+            if node.file {
+                node.fingerprint = self.fingerprint(relative_path, &node);
+            }
+            if node.file && config.links.hard && metadata.nlink() > 1 {
+                node.hard_link = self
+                    .entries
+                    .nodes
+                    .iter()
+                    .filter(|(p, n)| {
+                        n.file
+                            && n.device == node.device
+                            && n.inode == node.inode
+                            && p.as_str() < relative_path
                     })
-                }
+                    .map(|(p, _)| p.clone())
+                    .min()
+                    .unwrap_or_default();
             }
+            self.entries.nodes.insert(relative_path.to_string(), node);
         }
-
-        println!(
-            "Done creating changeset in {}",
-            HumanDuration(started.elapsed())
-        );
-        if changes.len() > 0 {
-            return Some(changes);
+    }
+    // keep the archived state of a path that was left unsynchronized,
+    // so it is reconciled again on the next run
+    pub fn keep_archived(&mut self, relative_path: &str) {
+        match self
+            .archive
+            .as_ref()
+            .and_then(|a| a.nodes.get(relative_path))
+        {
+            Some(node) => {
+                self.entries
+                    .nodes
+                    .insert(relative_path.to_string(), node.clone());
+            }
+            None => {
+                self.entries.nodes.remove(relative_path);
+            }
         }
-
-        None
     }
-    pub fn local_changes(&mut self) -> Option<Vec<Change>> {
-        println!("Detecting changed files...");
-        if self.first_run {
+    // resolve a path relative to the root, as sent by the other replica,
+    // refusing anything that could escape the root. that includes a path
+    // below a symlink, unless links are followed and it leads to a
+    // directory inside the root, in which case the path is resolved there.
+    // only the one escaped form of a path is accepted, so two spellings
+    // of a path can't get past a check made on one of them
+    pub fn local_path(&self, relative_path: &str) -> Option<PathBuf> {
+        if escape(&unescape(relative_path)) != relative_path {
             return None;
         }
-        let started = Instant::now();
-        let pb = ProgressBar::new_spinner();
-        pb.enable_steady_tick(200);
-        pb.set_style(
-            ProgressStyle::default_spinner()
-                .tick_chars("/|\\- ")
-                .template("{spinner:.dim.bold} found: {wide_msg}"),
-        );
-        let config = self.config.clone();
-        let rp = String::from(config.root.path.clone());
-
-        let mut archive = PathBuf::from(&rp);
-        archive.push(".runison-current");
-
-        let mut oldarchive = PathBuf::from(&rp);
-        oldarchive.push(".runison-previous");
-
-        // TODO: If there is no current, then there is no local changeset.
-        let current = std::fs::File::open(archive).unwrap();
-        let reader = BufReader::new(current);
-
-        /*
-        let ca: std::result::Result<HashMap<String, Node>, Box<bincode::ErrorKind>> =
-            bincode::deserialize_from(reader);
-        match ca {
-            Ok(current_archive) => {
-                let previous = std::fs::File::open(oldarchive).unwrap();
-                let preader = BufReader::new(previous);
-                let pa: std::result::Result<HashMap<String, Node>, Box<bincode::ErrorKind>> =
-                    bincode::deserialize_from(preader);
-                match pa {
-                    Ok(previous_archive) => {
-                        let mut changes = Vec::new();
-                        for (path, node) in &current_archive {
-                            if let Some(prev) = previous_archive.get(path) {
-                                // exists in both, check for change
-                                if !node.dir && node.modified != prev.modified {
-                                    // changed file
-                                    pb.set_message(node.name.clone().to_str().unwrap());
-                                    pb.tick();
-                                    changes.push(Change {
-                                        change_type: ChangeType::Clientmodify as i32,
-                                        node: Some(node.clone()),
-                                    })
-                                }
-                            } else {
-                                // doesn't exist in previous, is new file
-                                pb.set_message(node.name.clone().as_str());
-                                pb.tick();
-                                changes.push(Change {
-                                    change_type: ChangeType::Clientadd as i32,
-                                    node: Some(node.clone()),
-                                })
-                            }
-                        }
-                        for (path, prev) in previous_archive {
-                            match &current_archive.get(&path) {
-                                Some(_) => {}
-                                None => {
-                                    // current file is deleted
-
-                                    pb.set_message(prev.name.clone().as_str());
-                                    pb.tick();
-
-                                    changes.push(Change {
-                                        change_type: ChangeType::Clientdelete as i32,
-                                        node: Some(prev.clone()),
-                                    })
-                                }
-                            }
-                        }
-
-                        pb.finish_and_clear();
-                        println!(
-                            "Done scanning local changes in {}",
-                            HumanDuration(started.elapsed())
-                        );
-                        return Some(changes);
-                    }
-                    Err(e) => {
-                        println!("Error: {:?}", e);
-                        return None;
-                    }
+        let root = PathBuf::from(&self.config.root.path);
+        let mut path = root.clone();
+        for component in PathBuf::from(unescape(relative_path)).components() {
+            if path != root {
+                path = self.through_link(path)?;
+            }
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        Some(path)
+    }
+    // the local path of a file to read, which unlike local_path is also
+    // refused if it is a symlink that isn't followed
+    pub fn source_path(&self, relative_path: &str) -> Option<PathBuf> {
+        self.through_link(self.local_path(relative_path)?)
+    }
+    // the path relative to the root a relative path resolves to, which
+    // differs from it where it goes through a followed link
+    pub fn resolved(&self, relative_path: &str) -> Option<String> {
+        let path = self.local_path(relative_path)?;
+        let path = self.through_link(path.clone()).unwrap_or(path);
+        let root = PathBuf::from(&self.config.root.path);
+        let relative = match path.strip_prefix(&root) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => path
+                .strip_prefix(fs::canonicalize(&root).ok()?)
+                .ok()?
+                .to_path_buf(),
+        };
+        if relative.as_os_str().is_empty() {
+            Some(String::from("."))
+        } else {
+            Some(escape(relative.as_os_str()))
+        }
+    }
+    // a path that may be a symlink, resolved to where it leads if the
+    // link is followed. None for a link that isn't
+    fn through_link(&self, path: PathBuf) -> Option<PathBuf> {
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let root = std::path::Path::new(&self.config.root.path);
+                if self.config.links.follow && follows(root, &path) {
+                    fs::canonicalize(&path).ok()
+                } else {
+                    None
                 }
             }
-            Err(e) => {
-                println!("Error: {:?}", e);
-                return None;
+            _ => Some(path),
+        }
+    }
+    fn resolve(&self, relative_path: &str) -> io::Result<PathBuf> {
+        self.local_path(relative_path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid path {}", relative_path),
+            )
+        })
+    }
+    // give a path that was just received the attributes of the node
+    // it was received for
+    pub fn apply_attributes(&self, node: &Node) -> io::Result<()> {
+        let path = self.resolve(&node.relative_path)?;
+        node.apply_attributes(&path, &self.config.attributes)
+    }
+    // stage new contents for a file, to be committed into place
+    // once they have been received in full
+    pub fn stage(&self, relative_path: &str) -> io::Result<Staged> {
+        self.make_way(relative_path)?;
+        Staged::create(&self.resolve(relative_path)?)
+    }
+    // remove a directory that a file or link replaces, the way a deletion
+    // would: anything below it that wasn't indexed, or changed since,
+    // keeps it in place and the replacement fails
+    fn make_way(&self, relative_path: &str) -> io::Result<()> {
+        let path = self.resolve(relative_path)?;
+        if !fs::symlink_metadata(&path)
+            .map(|m| m.is_dir())
+            .unwrap_or(false)
+        {
+            return Ok(());
+        }
+        match self.entries.nodes.get(relative_path) {
+            Some(node) if node.dir => self.remove(node),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} became a directory since it was indexed", relative_path),
+            )),
+        }
+    }
+    // copy the file of a node to a path that is free on this replica.
+    // like received contents, the copy is checked against the node, so
+    // a file that changed since it was indexed isn't passed off for it
+    pub fn copy(&self, node: &Node, target: &str) -> io::Result<()> {
+        let path = self.resolve(target)?;
+        if self.entries.nodes.contains_key(target) || fs::symlink_metadata(&path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", target),
+            ));
+        }
+        if !node.file {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't a file", node.relative_path),
+            ));
+        }
+        let source = self.source_path(&node.relative_path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid path {}", node.relative_path),
+            )
+        })?;
+        let mut file = File::open(source)?;
+        let mut staged = Staged::create(&path)?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            let n = match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            staged.write_at(offset, &buf[..n])?;
+            offset += n as u64;
+        }
+        staged.commit(node)?;
+        self.apply_attributes(&Node {
+            relative_path: target.to_string(),
+            ..node.clone()
+        })
+    }
+    // create a directory, replacing a file that is in its way
+    pub fn make_dir(&self, relative_path: &str) -> io::Result<()> {
+        let path = self.resolve(relative_path)?;
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            if metadata.is_dir() {
+                return Ok(());
             }
+            fs::remove_file(&path)?;
         }
-         */
-        None
+        fs::create_dir_all(&path)
+    }
+    // give a file the contents of its hard link leader by linking it to
+    // the leader, which has to hold the contents of the node already
+    pub fn hard_link(&self, node: &Node) -> io::Result<()> {
+        let leader = match self.entries.nodes.get(&node.hard_link) {
+            Some(leader) if self.config.links.hard && !node.hard_link.is_empty() => leader,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no hard link leader for {}", node.relative_path),
+                ))
+            }
+        };
+        if leader.len != node.len || leader.fingerprint != node.fingerprint {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} differs from {}", node.hard_link, node.relative_path),
+            ));
+        }
+        let source = self.resolve(&node.hard_link)?;
+        let path = self.resolve(&node.relative_path)?;
+        // renaming a link over another link of the same file does nothing
+        if let (Ok(a), Ok(b)) = (fs::symlink_metadata(&source), fs::symlink_metadata(&path)) {
+            if a.dev() == b.dev() && a.ino() == b.ino() {
+                return Ok(());
+            }
+        }
+        self.make_way(&node.relative_path)?;
+        let parent = path.parent().unwrap_or(&path);
+        fs::create_dir_all(parent)?;
+        let temp = temp_next_to(&path);
+        fs::hard_link(&source, &temp)?;
+        fs::rename(&temp, &path).map_err(|e| {
+            let _ = fs::remove_file(&temp);
+            e
+        })
+    }
+    // create the symlink of a node, replacing whatever is at its path
+    pub fn make_link(&self, node: &Node) -> io::Result<()> {
+        if node.escapes_root() && self.config.links.reject_escaping {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} links outside of the root", node.relative_path),
+            ));
+        }
+        let path = self.resolve(&node.relative_path)?;
+        self.make_way(&node.relative_path)?;
+        let parent = path.parent().unwrap_or(&path);
+        fs::create_dir_all(parent)?;
+        // created next to the target and renamed into place, like
+        // staged contents, so the path is never missing
+        let temp = temp_next_to(&path);
+        std::os::unix::fs::symlink(OsStr::from_bytes(&node.link_target), &temp)?;
+        fs::rename(&temp, &path).map_err(|e| {
+            let _ = fs::remove_file(&temp);
+            e
+        })
+    }
+    // delete the path of a node from this replica. a directory is
+    // emptied of what was indexed below it, deepest paths first, so
+    // ignored files and anything that appeared since keep it in place
+    pub fn remove(&self, node: &Node) -> io::Result<()> {
+        let prefix = format!("{}/", node.relative_path);
+        let mut below: Vec<&Node> = self
+            .entries
+            .nodes
+            .iter()
+            .filter(|(path, _)| path.starts_with(&prefix))
+            .map(|(_, node)| node)
+            .collect();
+        // a path sorts before everything below it
+        below.sort_by(|a, b| b.relative_path.cmp(&a.relative_path));
+        for node in below {
+            self.remove_one(node)?;
+        }
+        self.remove_one(node)
+    }
+    // delete a single path, refusing to delete a file that changed
+    // after the node was indexed or a directory that isn't empty
+    fn remove_one(&self, node: &Node) -> io::Result<()> {
+        let path = self.resolve(&node.relative_path)?;
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            // already gone, maybe with a directory above it
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound
+                    || e.raw_os_error() == Some(libc::ENOTDIR) =>
+            {
+                return Ok(())
+            }
+            Err(e) => return Err(e),
+        };
+        if metadata.is_dir() {
+            return fs::remove_dir(&path).map_err(|e| match e.raw_os_error() {
+                Some(libc::ENOTEMPTY) => io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "{} holds files that aren't synchronized",
+                        node.relative_path
+                    ),
+                ),
+                _ => e,
+            });
+        }
+        if metadata.is_file()
+            && (metadata.len() != node.len || metadata.mtime() as u64 != node.mod_seconds)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} changed since it was indexed", node.relative_path),
+            ));
+        }
+        fs::remove_file(&path)
+    }
+    // the state of this replica as seen by the reconciler
+    pub fn replica(&self) -> Replica {
+        Replica {
+            archive: self.archive.as_ref(),
+            current: &self.entries,
+            names: self.names(),
+        }
+    }
+    // how the file system of this replica treats names
+    pub fn names(&self) -> Names {
+        Names {
+            case_insensitive: self.config.names.case_insensitive,
+            normalization: self.config.names.normalization,
+        }
+    }
+    // reconcile a client replica against this one
+    pub fn plan(&self, request: &ChangeSetRequest) -> Plan {
+        println!("Reconciling replicas...");
+
+        let started = Instant::now();
+        // the client may have indexed more than both replicas synchronize
+        let selected = |entries: &Entries| Entries {
+            nodes: entries
+                .nodes
+                .iter()
+                .filter(|(path, _)| self.selected(path))
+                .map(|(path, node)| (path.clone(), node.clone()))
+                .collect(),
+        };
+        let current = request.current.as_ref().map(selected).unwrap_or_default();
+        let archive = request.archive.as_ref().map(selected);
+        let client = Replica {
+            archive: archive.as_ref(),
+            current: &current,
+            names: request.names.clone().unwrap_or_default(),
+        };
+        let plan = reconcile(&client, &self.replica());
+        println!("Done reconciling in {}", HumanDuration(started.elapsed()));
+        plan
     }
 }
 
-// check ignored files and directories, returning true if
-// the current entry should be ignored
-pub fn ignored(entry: &DirEntry, config: &Config) -> bool {
-    for pat in config.ignore.path.clone() {
-        if Pattern::new(&pat)
-            .unwrap()
-            .matches(entry.path().to_str().unwrap())
-        {
-            return true;
+// a path and everything below it that isn't ignored, reading directories
+// in parallel. following links, a directory that is one of its own
+// ancestors is recorded but not walked into again, and a dangling link
+// is recorded as a link
+fn walk(
+    filter: &Filter,
+    follow: bool,
+    root: &std::path::Path,
+    path: PathBuf,
+    is_dir: bool,
+    ancestors: &[(u64, u64)],
+) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut ancestors = ancestors.to_vec();
+    if is_dir && follow {
+        match fs::metadata(&path) {
+            Ok(metadata) if ancestors.contains(&(metadata.dev(), metadata.ino())) => {
+                // a link looping back, anything else in a loop is left out
+                if fs::symlink_metadata(&path)
+                    .map(|m| m.file_type().is_symlink())
+                    .unwrap_or(false)
+                {
+                    found.push(path);
+                }
+                return found;
+            }
+            Ok(metadata) => ancestors.push((metadata.dev(), metadata.ino())),
+            Err(_) => {}
         }
     }
-    if Pattern::new(".runison-*")
-        .unwrap()
-        .matches(entry.path().file_name().unwrap().to_str().unwrap())
-    {
-        return true;
+    let entries = if is_dir {
+        fs::read_dir(&path).ok()
+    } else {
+        None
+    };
+    found.push(path);
+    let children: Vec<(PathBuf, bool)> = entries
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let child = entry.path();
+            let file_type = entry.file_type().ok()?;
+            let is_dir = file_type.is_dir()
+                || (follow
+                    && file_type.is_symlink()
+                    && follows(root, &child)
+                    && fs::metadata(&child).map(|m| m.is_dir()).unwrap_or(false));
+            if filter.ignored(&child, is_dir) {
+                None
+            } else {
+                Some((child, is_dir))
+            }
+        })
+        .collect();
+    found.extend(
+        children
+            .into_par_iter()
+            .flat_map_iter(|(child, is_dir)| walk(filter, follow, root, child, is_dir, &ancestors))
+            .collect::<Vec<_>>(),
+    );
+    found
+}
+
+// a selected subtree as written in a configuration or on the command
+// line, without trailing slashes and with "." for the whole root
+fn subtree(path: &str) -> String {
+    match path.trim_end_matches('/') {
+        "" => String::from("."),
+        path => path.to_string(),
     }
-    for pat in config.ignore.name.clone() {
-        if Pattern::new(&pat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+    use std::collections::BTreeSet;
+    use std::os::unix::fs::symlink;
+
+    // index a replica on a pool of the given number of threads
+    fn index(config: &Config, threads: usize) -> Entries {
+        let mut synchronizer = Synchronizer::new(config.clone()).unwrap();
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
             .unwrap()
-            .matches(entry.path().file_name().unwrap().to_str().unwrap())
-        {
-            return true;
+            .install(|| synchronizer.index());
+        synchronizer.entries
+    }
+
+    #[test]
+    fn parallel_walk_finds_what_a_serial_one_does() {
+        let root = std::env::temp_dir().join(format!("runison-walk-{:08x}", rand::random::<u32>()));
+        for dir in &["cache", "sub/node_modules", "sub/deep/er"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in &[
+            "a.txt",
+            "keep.log",
+            "skip.log",
+            "cache/big",
+            "sub/b.txt",
+            "sub/node_modules/x",
+            "sub/deep/er/c.txt",
+        ] {
+            fs::write(root.join(file), file).unwrap();
+        }
+        fs::write(root.join(".runisonignore"), "*.log\n!keep.log\ncache/\n").unwrap();
+        // a followed link to a directory, and one looping back to the root
+        symlink("sub", root.join("linked")).unwrap();
+        symlink("..", root.join("sub/loop")).unwrap();
+
+        let config: Config = Figment::new()
+            .merge(Toml::string(&format!(
+                "[root]\npath = {:?}\n[ignore]\nname = [\"node_modules\"]\n[links]\nfollow = true\n",
+                root.to_str().unwrap()
+            )))
+            .extract()
+            .unwrap();
+        let serial = index(&config, 1);
+        let parallel = index(&config, 8);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(serial, parallel);
+        let paths: BTreeSet<&str> = serial.nodes.keys().map(|path| path.as_str()).collect();
+        for path in &[
+            "a.txt",
+            "keep.log",
+            "linked/b.txt",
+            "linked/deep/er/c.txt",
+            "sub/loop",
+        ] {
+            assert!(paths.contains(path), "{} missing from {:?}", path, paths);
+        }
+        for path in &["skip.log", "cache", "sub/node_modules", "sub/loop/a.txt"] {
+            assert!(!paths.contains(path), "{} indexed in {:?}", path, paths);
         }
     }
-    false
 }
@@ -0,0 +1,42 @@
+use std::{fs, io};
+
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+
+use crate::config::Tls;
+
+// the certificate, key and CA of a [tls] section, None if TLS is not
+// configured. a partial section is an error rather than a silent
+// fallback to plaintext
+fn load(tls: &Tls) -> io::Result<Option<(Identity, Certificate)>> {
+    match (&tls.cert, &tls.key, &tls.ca) {
+        (None, None, None) => Ok(None),
+        (Some(cert), Some(key), Some(ca)) => {
+            let identity = Identity::from_pem(fs::read(cert)?, fs::read(key)?);
+            let ca = Certificate::from_pem(fs::read(ca)?);
+            Ok(Some((identity, ca)))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "[tls] needs all of cert, key and ca",
+        )),
+    }
+}
+
+// server side of mutual TLS: present our certificate and only accept
+// clients whose certificate is signed by the configured CA
+pub fn server_config(tls: &Tls) -> io::Result<Option<ServerTlsConfig>> {
+    Ok(load(tls)?
+        .map(|(identity, ca)| ServerTlsConfig::new().identity(identity).client_ca_root(ca)))
+}
+
+// client side of mutual TLS: present our certificate and only trust
+// a server whose certificate is signed by the configured CA
+pub fn client_config(tls: &Tls) -> io::Result<Option<ClientTlsConfig>> {
+    Ok(load(tls)?.map(|(identity, ca)| {
+        let config = ClientTlsConfig::new().identity(identity).ca_certificate(ca);
+        match &tls.domain {
+            Some(domain) => config.domain_name(domain.clone()),
+            None => config,
+        }
+    }))
+}
@@ -0,0 +1,259 @@
+use std::{
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Channel, Request};
+
+use crate::config::Transfer;
+use crate::node::fingerprint;
+use crate::runison::synchronizer_client::SynchronizerClient;
+use crate::runison::*;
+
+// size of the chunks file contents are streamed in
+pub const CHUNK_SIZE: usize = 64 * 1024;
+// size of the chunks of files of at least [transfer] stream_size bytes
+pub const STREAM_CHUNK_SIZE: usize = 8 * 1024;
+
+// how many chunks of a file of len bytes the sender reads ahead of the
+// ones sent. a streamed file has a single one, so only a few small
+// chunks of it are held in memory at any time
+pub fn read_ahead(len: u64, transfer: &Transfer) -> usize {
+    if len >= transfer.stream_size {
+        1
+    } else {
+        4
+    }
+}
+
+// the regions of a file that hold data, as offset and length. whatever
+// lies between them is a hole, which reads as zeros
+pub fn data_extents(file: &File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            let error = io::Error::last_os_error();
+            // no more data, the rest of the file is a hole
+            if error.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(error);
+        }
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        extents.push((start as u64, (end - start) as u64));
+        offset = end as u64;
+    }
+    Ok(extents)
+}
+
+// read a file and hand it to send one chunk at a time, stopping early
+// if send returns false because the other end went away. only the data
+// of sparse files is read. an empty file, or one that is all hole,
+// still produces a single empty chunk so the receiver learns about it
+pub fn read_chunks<F>(
+    path: &Path,
+    relative_path: &str,
+    transfer: &Transfer,
+    mut send: F,
+) -> io::Result<()>
+where
+    F: FnMut(Chunk) -> bool,
+{
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let chunk_size = if metadata.len() >= transfer.stream_size {
+        STREAM_CHUNK_SIZE
+    } else {
+        CHUNK_SIZE
+    };
+    // a dense file is read up to wherever it ends by now
+    let extents = if transfer.sparse && metadata.blocks() * 512 < metadata.len() {
+        data_extents(&file, metadata.len()).ok()
+    } else {
+        None
+    }
+    .unwrap_or_else(|| vec![(0, u64::MAX)]);
+    let mut sent = false;
+    for (start, len) in extents {
+        file.seek(SeekFrom::Start(start))?;
+        let end = start.saturating_add(len);
+        let mut offset = start;
+        while offset < end {
+            let mut data = vec![0; (end - offset).min(chunk_size as u64) as usize];
+            let n = file.read(&mut data)?;
+            if n == 0 {
+                break;
+            }
+            data.truncate(n);
+            let chunk = Chunk {
+                relative_path: relative_path.to_string(),
+                offset,
+                data,
+                node: None,
+            };
+            if !send(chunk) {
+                return Ok(());
+            }
+            sent = true;
+            offset += n as u64;
+        }
+    }
+    if !sent {
+        send(Chunk {
+            relative_path: relative_path.to_string(),
+            offset: 0,
+            data: Vec::new(),
+            node: None,
+        });
+    }
+    Ok(())
+}
+
+// a temporary path in the same directory as a file, so it can be renamed
+// over the file. the .runison- prefix keeps it out of the index
+pub fn temp_next_to(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(
+        ".runison-tmp-{}-{:08x}",
+        name,
+        rand::random::<u32>()
+    ))
+}
+
+// new contents for a file, written to a temporary file next to the
+// target and only moved into place once they have been verified.
+// dropping a Staged without committing it removes the temporary file,
+// so an interrupted transfer never leaves a half-written file behind
+pub struct Staged {
+    target: PathBuf,
+    temp: PathBuf,
+    file: File,
+    committed: bool,
+}
+
+impl Staged {
+    pub fn create(target: &Path) -> io::Result<Staged> {
+        let parent = match target.parent() {
+            Some(parent) => parent,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("can't stage {:?}", target),
+                ))
+            }
+        };
+        fs::create_dir_all(parent)?;
+        let temp = temp_next_to(target);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+        Ok(Staged {
+            target: target.to_path_buf(),
+            temp,
+            file,
+            committed: false,
+        })
+    }
+    pub fn write(&mut self, chunk: &Chunk) -> io::Result<()> {
+        self.write_at(chunk.offset, &chunk.data)
+    }
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)
+    }
+    // check the staged contents against the node they were sent for,
+    // flush them to disk and atomically move them into place
+    pub fn commit(mut self, node: &Node) -> io::Result<u64> {
+        self.file.flush()?;
+        // the holes of a sparse file were never written, the last one
+        // only exists once the file has its full length
+        if node.sparse && self.file.metadata()?.len() < node.len {
+            self.file.set_len(node.len)?;
+        }
+        let len = self.file.metadata()?.len();
+        if len != node.len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected {} bytes, received {}", node.len, len),
+            ));
+        }
+        if !node.fingerprint.is_empty() && fingerprint(&self.temp)? != node.fingerprint {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "fingerprint mismatch",
+            ));
+        }
+        self.file.sync_all()?;
+        fs::rename(&self.temp, &self.target)?;
+        self.committed = true;
+        // make the rename itself durable
+        if let Some(parent) = self.target.parent() {
+            File::open(parent)?.sync_all()?;
+        }
+        Ok(len)
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+// stream a local file to the server, returning the number of bytes
+// the server wrote. the node travels with the first chunk so the server
+// can verify the contents before moving them into place
+pub async fn push(
+    client: &mut SynchronizerClient<Channel>,
+    path: PathBuf,
+    node: Node,
+    transfer: Transfer,
+) -> Result<u64, Box<dyn Error>> {
+    let (tx, rx) = mpsc::channel(read_ahead(node.len, &transfer));
+    let reader = tokio::task::spawn_blocking(move || {
+        let relative_path = node.relative_path.clone();
+        let mut node = Some(node);
+        read_chunks(&path, &relative_path, &transfer, |mut chunk| {
+            chunk.node = node.take();
+            tx.blocking_send(chunk).is_ok()
+        })
+    });
+    let response = client
+        .push_file(Request::new(ReceiverStream::new(rx)))
+        .await?;
+    reader.await??;
+    Ok(response.into_inner().len)
+}
+
+// stream a server file into staged contents, committing them once
+// they match the server node. returns the number of bytes written
+pub async fn pull(
+    client: &mut SynchronizerClient<Channel>,
+    mut staged: Staged,
+    node: &Node,
+) -> Result<u64, Box<dyn Error>> {
+    let mut stream = client
+        .pull_file(Request::new(FileRequest {
+            relative_path: node.relative_path.clone(),
+        }))
+        .await?
+        .into_inner();
+    while let Some(chunk) = stream.message().await? {
+        staged.write(&chunk)?;
+    }
+    Ok(staged.commit(node)?)
+}
@@ -0,0 +1,167 @@
+use std::{env, error::Error, fs, path::PathBuf, process::Command};
+
+use console::{style, StyledObject, Term};
+use tonic::transport::Channel;
+
+use crate::runison::synchronizer_client::SynchronizerClient;
+use crate::runison::*;
+use crate::synchronizer::Synchronizer;
+use crate::transfer::{self, Staged};
+
+const HELP: &str = "  f, enter  follow the suggested action
+  >         propagate from client to server
+  <         propagate from server to client
+  /         skip this path
+  d         show the differences between the replicas
+  a         follow the suggested action for all remaining paths
+  q         skip all remaining paths
+  ?         show this help";
+
+fn kind(node: Option<&Node>) -> &'static str {
+    match node {
+        None => "absent",
+        Some(node) if node.dir => "dir",
+        Some(node) if node.symlink => "link",
+        Some(_) => "file",
+    }
+}
+
+fn arrow(item: &PlanItem, choice: Option<Action>) -> StyledObject<&'static str> {
+    match choice {
+        Some(Action::LeftToRight) => style("---->").green(),
+        Some(Action::RightToLeft) => style("<----").green(),
+        _ if item.action() == Action::Conflict => style("<-?->").red(),
+        _ if item.action() == Action::Collision => style("<-!->").red(),
+        _ => style("skip ").yellow(),
+    }
+}
+
+// one line describing a path and what is going to happen to it,
+// client on the left and server on the right
+fn describe(item: &PlanItem, choice: Option<Action>) -> String {
+    let line = format!(
+        "{:<6}  {}  {:<6}  {}",
+        kind(item.client.as_ref()),
+        arrow(item, choice),
+        kind(item.server.as_ref()),
+        item.relative_path
+    );
+    if item.collides_with.is_empty() {
+        line
+    } else {
+        format!("{}  (collides with {})", line, item.collides_with)
+    }
+}
+
+// print every path that needs attention with the action chosen for it
+pub fn print_choices(plan: &Plan, choices: &[Option<Action>]) {
+    for (item, choice) in plan.items.iter().zip(choices) {
+        if !item.in_sync() {
+            println!("{}", describe(item, *choice));
+        }
+    }
+}
+
+// walk the plan one path at a time and let the user choose what to do
+// with each. returns the chosen action for every item of the plan,
+// None for paths that are skipped or already in sync
+pub async fn review(
+    plan: &Plan,
+    client: &mut SynchronizerClient<Channel>,
+    synchronizer: &Synchronizer,
+) -> Result<Vec<Option<Action>>, Box<dyn Error>> {
+    let term = Term::stdout();
+    let mut choices: Vec<Option<Action>> = plan
+        .items
+        .iter()
+        .map(|item| item.default_action())
+        .collect();
+    let mut follow_all = false;
+    for (i, item) in plan.items.iter().enumerate() {
+        if item.in_sync() || follow_all {
+            continue;
+        }
+        // there is nothing to choose for a collision until it is renamed
+        if item.action() == Action::Collision {
+            term.write_line(&describe(item, None))?;
+            continue;
+        }
+        loop {
+            term.write_str(&format!("{}  ", describe(item, choices[i])))?;
+            let key = term.read_char()?;
+            term.write_line("")?;
+            match key {
+                'f' | '\n' | '\r' => break,
+                '>' => {
+                    choices[i] = Some(Action::LeftToRight);
+                    break;
+                }
+                '<' => {
+                    choices[i] = Some(Action::RightToLeft);
+                    break;
+                }
+                '/' => {
+                    choices[i] = None;
+                    break;
+                }
+                'd' => {
+                    if let Err(e) = diff(item, client, synchronizer).await {
+                        term.write_line(&format!("Error: {}", e))?;
+                    }
+                }
+                'a' => {
+                    follow_all = true;
+                    break;
+                }
+                'q' => {
+                    for choice in choices[i..].iter_mut() {
+                        *choice = None;
+                    }
+                    return Ok(choices);
+                }
+                _ => term.write_line(HELP)?,
+            }
+        }
+    }
+    Ok(choices)
+}
+
+// show the differences between both copies of a file by fetching the
+// server copy into a temporary file and running diff on the two
+async fn diff(
+    item: &PlanItem,
+    client: &mut SynchronizerClient<Channel>,
+    synchronizer: &Synchronizer,
+) -> Result<(), Box<dyn Error>> {
+    if item
+        .client
+        .iter()
+        .chain(item.server.iter())
+        .any(|n| !n.file)
+    {
+        return Err("only files can be compared".into());
+    }
+    let local = match (&item.client, synchronizer.local_path(&item.relative_path)) {
+        (Some(_), Some(path)) => path,
+        _ => PathBuf::from("/dev/null"),
+    };
+    let mut fetched = None;
+    if let Some(server) = &item.server {
+        let temp = env::temp_dir().join(format!("runison-diff-{:08x}", rand::random::<u32>()));
+        transfer::pull(client, Staged::create(&temp)?, server).await?;
+        fetched = Some(temp);
+    }
+    let remote = fetched
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("/dev/null"));
+    let status = Command::new("diff")
+        .arg("-u")
+        .arg(&local)
+        .arg(&remote)
+        .status();
+    if let Some(temp) = fetched {
+        let _ = fs::remove_file(temp);
+    }
+    status?;
+    Ok(())
+}